@@ -0,0 +1,439 @@
+use anyhow::{anyhow, Result};
+use log::debug;
+use serde::de::DeserializeOwned;
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::sync::Arc;
+
+use crate::http::{HttpRequest, HttpResponse, Isolation};
+use crate::transport::HttpTransport;
+
+/// Status of a transaction as reported by Esplora
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+pub struct TxStatus {
+    pub confirmed: bool,
+    pub block_height: Option<u32>,
+    pub block_hash: Option<String>,
+    pub block_time: Option<u64>,
+}
+
+/// Output being spent by a transaction input
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+pub struct PrevOut {
+    pub value: u64,
+    pub scriptpubkey: String,
+}
+
+/// Transaction input
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+pub struct Vin {
+    pub txid: String,
+    pub vout: u32,
+    /// `None` for coinbase inputs
+    pub prevout: Option<PrevOut>,
+    pub scriptsig: String,
+    #[serde(default)]
+    pub witness: Vec<String>,
+    pub sequence: u32,
+    pub is_coinbase: bool,
+}
+
+/// Transaction output
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+pub struct Vout {
+    pub value: u64,
+    pub scriptpubkey: String,
+}
+
+/// Transaction as returned by `/tx/:txid` and the history endpoints
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+pub struct Tx {
+    pub txid: String,
+    pub version: i32,
+    pub locktime: u32,
+    pub vin: Vec<Vin>,
+    pub vout: Vec<Vout>,
+    pub size: usize,
+    pub weight: u64,
+    pub status: TxStatus,
+    pub fee: u64,
+}
+
+/// Block header information as returned by `/block/:hash`
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+pub struct Block {
+    pub id: String,
+    pub height: u32,
+    pub version: i32,
+    pub timestamp: u64,
+    pub tx_count: u32,
+    pub size: usize,
+    pub weight: u64,
+    pub merkle_root: String,
+    /// `None` for the genesis block
+    pub previousblockhash: Option<String>,
+    pub mediantime: u64,
+    pub nonce: u32,
+    pub bits: u32,
+    pub difficulty: f64,
+}
+
+/// Async Esplora API client running over any [`HttpTransport`]
+#[derive(Clone)]
+pub struct EsploraClient {
+    base_url: String,
+    transport: Arc<dyn HttpTransport>,
+    /// Isolation applied to every request, e.g. one group per wallet
+    isolation: Isolation,
+    /// Give every address/script hash query and broadcast its own circuit
+    isolate_queries: bool,
+}
+
+impl EsploraClient {
+    /// Create a client for the Esplora instance at `base_url` (e.g. `https://blockstream.info/api`)
+    pub fn new(base_url: &str, transport: Arc<dyn HttpTransport>) -> Self {
+        Self {
+            base_url: base_url.trim_end_matches('/').to_string(),
+            transport,
+            isolation: Isolation::None,
+            isolate_queries: false,
+        }
+    }
+
+    /// Keep this client's requests on circuits of their own, shared only with
+    /// clients using the same group name (e.g. the wallet id)
+    pub fn with_isolation_group(mut self, group: &str) -> Self {
+        self.isolation = Isolation::Group(group.to_string());
+        self
+    }
+
+    /// Send each address and script hash query, and each broadcast, on a
+    /// separate circuit so the exit cannot link them together
+    pub fn with_query_isolation(mut self, enabled: bool) -> Self {
+        self.isolate_queries = enabled;
+        self
+    }
+
+    /// Base URL of the Esplora instance
+    pub fn url(&self) -> &str {
+        &self.base_url
+    }
+
+    /// Get a transaction by its txid, or `None` if the server does not know it
+    pub async fn get_tx(&self, txid: &str) -> Result<Option<Tx>> {
+        validate_hash("txid", txid)?;
+        self.get_opt_json(&format!("/tx/{}", txid), self.isolation.clone())
+            .await
+    }
+
+    /// Get the confirmation status of a transaction
+    pub async fn get_tx_status(&self, txid: &str) -> Result<TxStatus> {
+        validate_hash("txid", txid)?;
+        self.get_json(&format!("/tx/{}/status", txid), self.isolation.clone())
+            .await
+    }
+
+    /// Get a block header summary by its hash
+    pub async fn get_block(&self, hash: &str) -> Result<Block> {
+        validate_hash("block hash", hash)?;
+        self.get_json(&format!("/block/{}", hash), self.isolation.clone())
+            .await
+    }
+
+    /// Get the height of the current chain tip
+    pub async fn get_height(&self) -> Result<u32> {
+        let text = self.get_text("/blocks/tip/height").await?;
+        text.trim()
+            .parse()
+            .map_err(|e| anyhow!("Invalid tip height {:?}: {}", text, e))
+    }
+
+    /// Get the transaction history of an address.
+    /// Pass the last txid seen to page through confirmed transactions.
+    pub async fn get_address_txs(&self, address: &str, last_seen: Option<&str>) -> Result<Vec<Tx>> {
+        // Base58 and bech32 addresses are alphanumeric
+        if address.is_empty() || !address.bytes().all(|b| b.is_ascii_alphanumeric()) {
+            return Err(anyhow!("Invalid address: {:?}", address));
+        }
+        if let Some(txid) = last_seen {
+            validate_hash("txid", txid)?;
+        }
+        let path = match last_seen {
+            Some(txid) => format!("/address/{}/txs/chain/{}", address, txid),
+            None => format!("/address/{}/txs", address),
+        };
+        self.get_json(&path, self.query_isolation()).await
+    }
+
+    /// Get the transaction history of a script hash (hex, as used by Electrum).
+    /// Pass the last txid seen to page through confirmed transactions.
+    pub async fn get_scripthash_txs(
+        &self,
+        scripthash: &str,
+        last_seen: Option<&str>,
+    ) -> Result<Vec<Tx>> {
+        validate_hash("script hash", scripthash)?;
+        if let Some(txid) = last_seen {
+            validate_hash("txid", txid)?;
+        }
+        let path = match last_seen {
+            Some(txid) => format!("/scripthash/{}/txs/chain/{}", scripthash, txid),
+            None => format!("/scripthash/{}/txs", scripthash),
+        };
+        self.get_json(&path, self.query_isolation()).await
+    }
+
+    /// Get fee estimates in sat/vB, keyed by confirmation target in blocks
+    pub async fn get_fee_estimates(&self) -> Result<HashMap<u16, f64>> {
+        self.get_json("/fee-estimates", self.isolation.clone())
+            .await
+    }
+
+    /// Broadcast a raw transaction (hex encoded) and return its txid
+    pub async fn broadcast(&self, tx_hex: &str) -> Result<String> {
+        let url = format!("{}/tx", self.base_url);
+        let response = self
+            .send(
+                HttpRequest::post(&url, tx_hex.as_bytes().to_vec())
+                    .with_isolation(self.query_isolation()),
+            )
+            .await?;
+        let text = String::from_utf8_lossy(&response.body).trim().to_string();
+        if !response.is_success() {
+            return Err(anyhow!(
+                "Broadcast failed with status {}: {}",
+                response.status,
+                text
+            ));
+        }
+        Ok(text)
+    }
+
+    /// Isolation for requests that reveal which addresses or transactions are ours
+    fn query_isolation(&self) -> Isolation {
+        if self.isolate_queries {
+            Isolation::Unique
+        } else {
+            self.isolation.clone()
+        }
+    }
+
+    async fn get_json<T: DeserializeOwned>(&self, path: &str, isolation: Isolation) -> Result<T> {
+        self.get_opt_json(path, isolation)
+            .await?
+            .ok_or_else(|| anyhow!("Not found: {}", path))
+    }
+
+    async fn get_opt_json<T: DeserializeOwned>(
+        &self,
+        path: &str,
+        isolation: Isolation,
+    ) -> Result<Option<T>> {
+        let response = self.get(path, isolation).await?;
+        if response.status == 404 {
+            return Ok(None);
+        }
+        check_status(&response)?;
+        Ok(Some(serde_json::from_slice(&response.body)?))
+    }
+
+    async fn get_text(&self, path: &str) -> Result<String> {
+        let response = self.get(path, self.isolation.clone()).await?;
+        check_status(&response)?;
+        Ok(String::from_utf8(response.body)?)
+    }
+
+    async fn get(&self, path: &str, isolation: Isolation) -> Result<HttpResponse> {
+        let url = format!("{}{}", self.base_url, path);
+        self.send(HttpRequest::get(&url).with_isolation(isolation))
+            .await
+    }
+
+    /// Send a request to the Esplora API through the configured transport
+    async fn send(&self, request: HttpRequest) -> Result<HttpResponse> {
+        debug!("Esplora {} {}", request.method, request.url);
+        self.transport.send(request).await
+    }
+}
+
+/// Check that `value` is a 32-byte hash in hex, so it can go into a URL path as is
+fn validate_hash(kind: &str, value: &str) -> Result<()> {
+    if value.len() == 64 && value.bytes().all(|b| b.is_ascii_hexdigit()) {
+        Ok(())
+    } else {
+        Err(anyhow!("Invalid {}: {:?}", kind, value))
+    }
+}
+
+/// Turn a non-success status code into an error carrying the response body
+fn check_status(response: &HttpResponse) -> Result<()> {
+    if response.is_success() {
+        Ok(())
+    } else {
+        Err(anyhow!(
+            "Request failed with status code {}: {}",
+            response.status,
+            String::from_utf8_lossy(&response.body).trim()
+        ))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use async_trait::async_trait;
+
+    const GENESIS_HASH: &str = "000000000019d6689c085ae165831e934ff763ae46a2a6c172b3f1b60a8ce26f";
+
+    /// `/block/:hash` for the genesis block
+    const BLOCK_JSON: &str = r#"{
+        "id": "000000000019d6689c085ae165831e934ff763ae46a2a6c172b3f1b60a8ce26f",
+        "height": 0, "version": 1, "timestamp": 1231006505, "tx_count": 1,
+        "size": 285, "weight": 816,
+        "merkle_root": "4a5e1e4baab89f3a32518a88c31bc87f618f76673e2cc77ab2127b7afdeda33b",
+        "previousblockhash": null, "mediantime": 1231006505,
+        "nonce": 2083236893, "bits": 486604799, "difficulty": 1
+    }"#;
+
+    /// `/tx/:txid` for the first transaction spending a coinbase (block 170)
+    const TX_JSON: &str = r#"{
+        "txid": "f4184fc596403b9d638783cf57adfe4c75c605f6356fbc91338530e9831e9e16",
+        "version": 1, "locktime": 0,
+        "vin": [{
+            "txid": "0437cd7f8525ceed2324359c2d0ba26006d92d856a9c20fa0241106ee5a597c9",
+            "vout": 0,
+            "prevout": {
+                "scriptpubkey": "410411db93e1dcdb8a016b49840f8c53bc1eb68a382e97b1482ecad7b148a6909a5cb2e0eaddfb84ccf9744464f82e160bfa9b8b64f9d4c03f999b8643f656b412a3ac",
+                "scriptpubkey_asm": "OP_PUSHBYTES_65 0411db93e1dcdb8a016b49840f8c53bc1eb68a382e97b1482ecad7b148a6909a5cb2e0eaddfb84ccf9744464f82e160bfa9b8b64f9d4c03f999b8643f656b412a3 OP_CHECKSIG",
+                "scriptpubkey_type": "p2pk",
+                "value": 5000000000
+            },
+            "scriptsig": "47304402204e45e16932b8af514961a1d3a1a25fdf3f4f7732e9d624c6c61548ab5fb8cd410220181522ec8eca07de4860a4acdd12909d831cc56cbbac4622082221a8768d1d0901",
+            "scriptsig_asm": "OP_PUSHBYTES_71 304402204e45e16932b8af514961a1d3a1a25fdf3f4f7732e9d624c6c61548ab5fb8cd410220181522ec8eca07de4860a4acdd12909d831cc56cbbac4622082221a8768d1d0901",
+            "is_coinbase": false,
+            "sequence": 4294967295
+        }],
+        "vout": [
+            {
+                "scriptpubkey": "4104ae1a62fe09c5f51b13905f07f06b99a2f7159b2225f374cd378d71302fa28414e7aab37397f554a7df5f142c21c1b7303b8a0626f1baded5c72a704f7e6cd84cac",
+                "scriptpubkey_type": "p2pk",
+                "value": 1000000000
+            },
+            {
+                "scriptpubkey": "410411db93e1dcdb8a016b49840f8c53bc1eb68a382e97b1482ecad7b148a6909a5cb2e0eaddfb84ccf9744464f82e160bfa9b8b64f9d4c03f999b8643f656b412a3ac",
+                "scriptpubkey_type": "p2pk",
+                "value": 4000000000
+            }
+        ],
+        "size": 275, "weight": 1100, "fee": 0,
+        "status": {
+            "confirmed": true, "block_height": 170,
+            "block_hash": "00000000d1145790a8694403d4063f323d499e655c83426834d4ce2f8dd4a2ee",
+            "block_time": 1231731025
+        }
+    }"#;
+
+    /// `/tx/:txid` for the genesis coinbase, which has no prevout and no witness
+    const COINBASE_JSON: &str = r#"{
+        "txid": "4a5e1e4baab89f3a32518a88c31bc87f618f76673e2cc77ab2127b7afdeda33b",
+        "version": 1, "locktime": 0,
+        "vin": [{
+            "txid": "0000000000000000000000000000000000000000000000000000000000000000",
+            "vout": 4294967295, "prevout": null,
+            "scriptsig": "04ffff001d0104455468652054696d65732030332f4a616e2f32303039204368616e63656c6c6f72206f6e206272696e6b206f66207365636f6e64206261696c6f757420666f722062616e6b73",
+            "is_coinbase": true, "sequence": 4294967295
+        }],
+        "vout": [{
+            "scriptpubkey": "4104678afdb0fe5548271967f1a67130b7105cd6a828e03909a67962e0ea1f61deb649f6bc3f4cef38c4f35504e51ec112de5c384df7ba0b8d578a4c702b6bf11d5fac",
+            "scriptpubkey_type": "p2pk",
+            "value": 5000000000
+        }],
+        "size": 204, "weight": 816, "fee": 0,
+        "status": {
+            "confirmed": true, "block_height": 0,
+            "block_hash": "000000000019d6689c085ae165831e934ff763ae46a2a6c172b3f1b60a8ce26f",
+            "block_time": 1231006505
+        }
+    }"#;
+
+    #[test]
+    fn deserializes_block() {
+        let block: Block = serde_json::from_str(BLOCK_JSON).unwrap();
+        assert_eq!(block.id, GENESIS_HASH);
+        assert_eq!(block.height, 0);
+        assert_eq!(block.previousblockhash, None);
+        assert_eq!(block.nonce, 2083236893);
+        assert_eq!(block.difficulty, 1.0);
+    }
+
+    #[test]
+    fn deserializes_tx() {
+        let tx: Tx = serde_json::from_str(TX_JSON).unwrap();
+        assert_eq!(tx.vin.len(), 1);
+        assert_eq!(tx.vin[0].vout, 0);
+        assert_eq!(tx.vin[0].prevout.as_ref().unwrap().value, 5_000_000_000);
+        assert!(tx.vin[0].witness.is_empty());
+        let values: Vec<_> = tx.vout.iter().map(|vout| vout.value).collect();
+        assert_eq!(values, [1_000_000_000, 4_000_000_000]);
+        assert_eq!(tx.status.block_height, Some(170));
+
+        let coinbase: Tx = serde_json::from_str(COINBASE_JSON).unwrap();
+        assert!(coinbase.vin[0].is_coinbase);
+        assert_eq!(coinbase.vin[0].prevout, None);
+        assert!(coinbase.vin[0].witness.is_empty());
+        assert_eq!(coinbase.status.block_hash.as_deref(), Some(GENESIS_HASH));
+    }
+
+    #[test]
+    fn deserializes_tx_status() {
+        let confirmed: TxStatus = serde_json::from_str(
+            r#"{"confirmed": true, "block_height": 0, "block_hash": "000000000019d6689c085ae165831e934ff763ae46a2a6c172b3f1b60a8ce26f", "block_time": 1231006505}"#,
+        )
+        .unwrap();
+        assert_eq!(confirmed.block_hash.as_deref(), Some(GENESIS_HASH));
+        let unconfirmed: TxStatus = serde_json::from_str(r#"{"confirmed": false}"#).unwrap();
+        assert_eq!(
+            unconfirmed,
+            TxStatus {
+                confirmed: false,
+                block_height: None,
+                block_hash: None,
+                block_time: None,
+            }
+        );
+    }
+
+    /// Transport failing every request, for calls that must not send anything
+    struct NoTransport;
+
+    #[async_trait]
+    impl HttpTransport for NoTransport {
+        async fn send(&self, request: HttpRequest) -> Result<HttpResponse> {
+            panic!("unexpected request to {}", request.url);
+        }
+    }
+
+    #[tokio::test]
+    async fn invalid_ids_are_rejected_before_sending() {
+        let client = EsploraClient::new("http://esplora.invalid/api", Arc::new(NoTransport));
+        for id in [
+            "",
+            "abc",
+            "../blocks/tip/height",
+            &format!("{}/outspends", GENESIS_HASH),
+            &GENESIS_HASH.replace('0', "g"),
+            &format!("{}0", GENESIS_HASH),
+        ] {
+            assert!(client.get_tx(id).await.is_err(), "{:?}", id);
+            assert!(client.get_tx_status(id).await.is_err(), "{:?}", id);
+            assert!(client.get_block(id).await.is_err(), "{:?}", id);
+            assert!(client.get_scripthash_txs(id, None).await.is_err());
+            assert!(client
+                .get_address_txs("bc1qw508d6qejxtdg4y5r3zarvary0c5xw7kv8f3t4", Some(id))
+                .await
+                .is_err());
+        }
+        assert!(client.get_address_txs("bc1q/../x", None).await.is_err());
+        assert!(validate_hash("block hash", &GENESIS_HASH.to_uppercase()).is_ok());
+    }
+}
@@ -0,0 +1,1198 @@
+use anyhow::{anyhow, Result};
+use tokio::io::{AsyncBufRead, AsyncBufReadExt, AsyncRead, AsyncReadExt, AsyncWrite};
+
+/// Maximum size of a response status line plus headers
+const MAX_HEAD_SIZE: usize = 64 * 1024;
+/// Maximum number of response headers
+const MAX_HEADERS: usize = 100;
+/// Maximum size of a response body, however it is framed
+const MAX_BODY_SIZE: usize = 64 * 1024 * 1024;
+/// Maximum size of a request line plus headers received by the bridge
+const MAX_REQUEST_HEAD_SIZE: usize = 32 * 1024;
+/// Maximum number of request headers received by the bridge
+const MAX_REQUEST_HEADERS: usize = 100;
+
+/// Header list with case-insensitive lookup that keeps the original order
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct HeaderMap {
+    entries: Vec<(String, String)>,
+}
+
+impl HeaderMap {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Append a header, keeping any existing values with the same name
+    pub fn append(&mut self, name: &str, value: &str) {
+        self.entries.push((name.to_string(), value.to_string()));
+    }
+
+    /// Set a header, replacing any existing values with the same name
+    pub fn insert(&mut self, name: &str, value: &str) {
+        self.remove(name);
+        self.append(name, value);
+    }
+
+    /// Remove all values of a header
+    pub fn remove(&mut self, name: &str) {
+        self.entries.retain(|(n, _)| !n.eq_ignore_ascii_case(name));
+    }
+
+    /// First value of a header
+    pub fn get(&self, name: &str) -> Option<&str> {
+        self.entries
+            .iter()
+            .find(|(n, _)| n.eq_ignore_ascii_case(name))
+            .map(|(_, v)| v.as_str())
+    }
+
+    /// All values of a header in the order they were received
+    pub fn get_all<'a>(&'a self, name: &'a str) -> impl Iterator<Item = &'a str> + 'a {
+        self.entries
+            .iter()
+            .filter(move |(n, _)| n.eq_ignore_ascii_case(name))
+            .map(|(_, v)| v.as_str())
+    }
+
+    pub fn contains(&self, name: &str) -> bool {
+        self.get(name).is_some()
+    }
+
+    /// Whether a comma separated header (e.g. `Connection`) contains `token`
+    pub fn has_token(&self, name: &str, token: &str) -> bool {
+        self.get_all(name)
+            .flat_map(|v| v.split(','))
+            .any(|t| t.trim().eq_ignore_ascii_case(token))
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&str, &str)> {
+        self.entries.iter().map(|(n, v)| (n.as_str(), v.as_str()))
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+}
+
+impl<K: Into<String>, V: Into<String>> FromIterator<(K, V)> for HeaderMap {
+    fn from_iter<I: IntoIterator<Item = (K, V)>>(iter: I) -> Self {
+        Self {
+            entries: iter
+                .into_iter()
+                .map(|(k, v)| (k.into(), v.into()))
+                .collect(),
+        }
+    }
+}
+
+/// User-Agent of Tor Browser, which most Tor users send. It is the default
+/// for requests and for [`HeaderPolicy`], so wallet traffic blends in.
+pub const COMMON_USER_AGENT: &str =
+    "Mozilla/5.0 (Windows NT 10.0; rv:128.0) Gecko/20100101 Firefox/128.0";
+
+/// Headers kept by the default [`HeaderPolicy`], in the order they are sent
+const DEFAULT_ALLOWED_HEADERS: &[&str] = &[
+    "Host",
+    "User-Agent",
+    "Accept",
+    "Content-Type",
+    "Content-Length",
+    "Transfer-Encoding",
+    "Connection",
+];
+
+/// Headers that frame a message; kept even when the allowlist leaves them out
+const FRAMING_HEADERS: &[&str] = &["Content-Length", "Transfer-Encoding", "Connection"];
+
+/// Header normalization, so requests look the same whichever user, wallet
+/// or client version sent them: a fixed set of headers in a fixed order,
+/// with a common User-Agent (or none)
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct HeaderPolicy {
+    /// User-Agent sent instead of the caller's; `None` sends no User-Agent
+    pub user_agent: Option<String>,
+    /// Headers that are kept, in the order they are sent and with the spelling
+    /// given here. Everything else (cookies, `Accept-Language`, ...) is dropped.
+    pub allowed_headers: Vec<String>,
+}
+
+impl Default for HeaderPolicy {
+    fn default() -> Self {
+        Self {
+            user_agent: Some(COMMON_USER_AGENT.to_string()),
+            allowed_headers: DEFAULT_ALLOWED_HEADERS
+                .iter()
+                .map(|name| name.to_string())
+                .collect(),
+        }
+    }
+}
+
+impl HeaderPolicy {
+    /// Normalize a header list. `Host` leads and the framing headers follow
+    /// the allowed ones when the allowlist doesn't place them.
+    pub fn apply(&self, headers: &HeaderMap) -> HeaderMap {
+        let is_allowed = |name: &str| {
+            self.allowed_headers
+                .iter()
+                .any(|allowed| allowed.eq_ignore_ascii_case(name))
+        };
+        let mut order: Vec<&str> = self.allowed_headers.iter().map(String::as_str).collect();
+        if !is_allowed("host") {
+            order.insert(0, "Host");
+        }
+        for name in ["User-Agent"].iter().chain(FRAMING_HEADERS) {
+            if !is_allowed(name) {
+                order.push(name);
+            }
+        }
+
+        let mut normalized = HeaderMap::new();
+        for name in order {
+            if name.eq_ignore_ascii_case("user-agent") {
+                if let Some(user_agent) = &self.user_agent {
+                    normalized.append(name, user_agent);
+                }
+                continue;
+            }
+            for value in headers.get_all(name) {
+                normalized.append(name, value);
+            }
+        }
+        normalized
+    }
+}
+
+/// Tor circuit isolation requested for a request. Transports without Tor
+/// circuits ignore it.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Hash)]
+pub enum Isolation {
+    /// Share circuits with other requests of the same transport
+    #[default]
+    None,
+    /// Share circuits only with requests using the same group name,
+    /// e.g. one group per wallet or descriptor
+    Group(String),
+    /// Use a circuit not shared with any other request
+    Unique,
+}
+
+/// An HTTP request to be sent through one of the transports
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct HttpRequest {
+    /// Request method, e.g. `GET` or `POST`
+    pub method: String,
+    /// Absolute URL of the target resource
+    pub url: String,
+    /// Extra request headers
+    pub headers: HeaderMap,
+    /// Optional request body
+    pub body: Option<Vec<u8>>,
+    /// Circuit isolation for this request
+    pub isolation: Isolation,
+}
+
+impl HttpRequest {
+    /// Create a request with an arbitrary method for the given URL
+    pub fn new(method: &str, url: &str) -> Self {
+        Self {
+            method: method.to_ascii_uppercase(),
+            url: url.to_string(),
+            headers: HeaderMap::new(),
+            body: None,
+            isolation: Isolation::None,
+        }
+    }
+
+    /// Create a `GET` request for the given URL
+    pub fn get(url: &str) -> Self {
+        Self::new("GET", url)
+    }
+
+    /// Create a `POST` request for the given URL with a body
+    pub fn post(url: &str, body: Vec<u8>) -> Self {
+        Self::new("POST", url).with_body(body)
+    }
+
+    /// Add a header, replacing any previous value with the same name
+    pub fn with_header(mut self, name: &str, value: &str) -> Self {
+        self.headers.insert(name, value);
+        self
+    }
+
+    /// Set the request body. `Content-Length` is always derived from it.
+    pub fn with_body<B: Into<Vec<u8>>>(mut self, body: B) -> Self {
+        self.body = Some(body.into());
+        self
+    }
+
+    /// Request circuit isolation for this request
+    pub fn with_isolation(mut self, isolation: Isolation) -> Self {
+        self.isolation = isolation;
+        self
+    }
+
+    /// Serialize the request as HTTP/1.1 in origin-form, ready to be written
+    /// to a connection to the target host.
+    ///
+    /// `Host`, `User-Agent`, `Accept` and `Connection` are filled in unless the
+    /// caller set them; `Content-Length` is computed from the body and any
+    /// caller supplied value or `Transfer-Encoding` is dropped.
+    pub fn to_bytes(&self) -> Result<Vec<u8>> {
+        self.to_bytes_with_policy(None)
+    }
+
+    /// Like [`HttpRequest::to_bytes`], with the headers normalized by `policy`
+    /// after the defaults are filled in
+    pub fn to_bytes_with_policy(&self, policy: Option<&HeaderPolicy>) -> Result<Vec<u8>> {
+        validate_token(&self.method)
+            .map_err(|_| anyhow!("Invalid HTTP method: {:?}", self.method))?;
+        for (name, value) in self.headers.iter() {
+            validate_token(name).map_err(|_| anyhow!("Invalid header name: {:?}", name))?;
+            if value.bytes().any(|b| b == b'\r' || b == b'\n' || b == 0) {
+                return Err(anyhow!("Invalid value for header {}", name));
+            }
+        }
+
+        let url = url::Url::parse(&self.url)?;
+        let host = url.host_str().ok_or_else(|| anyhow!("No host in URL"))?;
+        // `port()` is `None` when the URL uses the scheme's default port
+        let host_header = match url.port() {
+            Some(port) => format!("{}:{}", host, port),
+            None => host.to_string(),
+        };
+        let mut target = url.path().to_string();
+        if target.is_empty() {
+            target.push('/');
+        }
+        if let Some(query) = url.query() {
+            target.push('?');
+            target.push_str(query);
+        }
+
+        // Host goes first, followed by the caller's headers
+        let mut headers = HeaderMap::new();
+        headers.append("Host", self.headers.get("host").unwrap_or(&host_header));
+        for (name, value) in self.headers.iter() {
+            let skip = ["host", "content-length", "transfer-encoding"];
+            if !skip.iter().any(|s| name.eq_ignore_ascii_case(s)) {
+                headers.append(name, value);
+            }
+        }
+        for (name, default) in [
+            ("User-Agent", COMMON_USER_AGENT),
+            ("Accept", "*/*"),
+            ("Connection", "close"),
+        ] {
+            if !headers.contains(name) {
+                headers.append(name, default);
+            }
+        }
+        // Methods that normally carry a body get an explicit zero length
+        match &self.body {
+            Some(body) => headers.append("Content-Length", &body.len().to_string()),
+            None if matches!(self.method.as_str(), "POST" | "PUT" | "PATCH") => {
+                headers.append("Content-Length", "0")
+            }
+            None => {}
+        }
+        if let Some(policy) = policy {
+            headers = policy.apply(&headers);
+        }
+
+        let mut out = format!("{} {} HTTP/1.1\r\n", self.method, target).into_bytes();
+        for (name, value) in headers.iter() {
+            out.extend_from_slice(format!("{}: {}\r\n", name, value).as_bytes());
+        }
+        out.extend_from_slice(b"\r\n");
+        if let Some(body) = &self.body {
+            out.extend_from_slice(body);
+        }
+        Ok(out)
+    }
+}
+
+/// Check that `s` is a non-empty RFC 9110 token (method or header name)
+fn validate_token(s: &str) -> Result<()> {
+    let valid = !s.is_empty()
+        && s.bytes()
+            .all(|b| b.is_ascii_alphanumeric() || b"!#$%&'*+-.^_`|~".contains(&b));
+    if valid {
+        Ok(())
+    } else {
+        Err(anyhow!("Invalid token: {:?}", s))
+    }
+}
+
+/// A byte stream HTTP can run over, e.g. an Arti `DataStream` or TLS on top of one
+pub trait HttpStream: AsyncRead + AsyncWrite + Unpin + Send {}
+
+impl<T: AsyncRead + AsyncWrite + Unpin + Send> HttpStream for T {}
+
+/// An HTTP response returned by one of the transports
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct HttpResponse {
+    /// Status code, e.g. `200`
+    pub status: u16,
+    /// Response headers in the order they were received
+    pub headers: HeaderMap,
+    /// Response body
+    pub body: Vec<u8>,
+}
+
+impl HttpResponse {
+    /// Whether the status code is in the 2xx range
+    pub fn is_success(&self) -> bool {
+        (200..300).contains(&self.status)
+    }
+
+    /// Interpret the body as UTF-8 text
+    pub fn text(&self) -> Result<&str> {
+        Ok(std::str::from_utf8(&self.body)?)
+    }
+}
+
+/// Read one HTTP/1.x response from `reader`.
+///
+/// The body is framed by `Transfer-Encoding: chunked`, `Content-Length` or,
+/// failing both, by the server closing the connection. `is_head` must be set
+/// for responses to `HEAD` requests, which never carry a body.
+pub async fn read_response<R: AsyncBufRead + Unpin>(
+    reader: &mut R,
+    is_head: bool,
+) -> Result<HttpResponse> {
+    Ok(read_response_keep_alive(reader, is_head).await?.0)
+}
+
+/// Like [`read_response`], but also reports whether the connection can be
+/// reused for another request afterwards
+pub async fn read_response_keep_alive<R: AsyncBufRead + Unpin>(
+    reader: &mut R,
+    is_head: bool,
+) -> Result<(HttpResponse, bool)> {
+    loop {
+        let head = read_head(reader).await?;
+        let (version, status, headers) = parse_head(&head)?;
+
+        // Skip interim responses such as `100 Continue`
+        if (100..200).contains(&status) && status != 101 {
+            continue;
+        }
+
+        // HTTP/1.1 defaults to persistent connections, HTTP/1.0 must opt in
+        let mut keep_alive = if version >= 1 {
+            !headers.has_token("connection", "close")
+        } else {
+            headers.has_token("connection", "keep-alive")
+        };
+
+        let body = if is_head || status == 204 || status == 304 || (100..200).contains(&status) {
+            Vec::new()
+        } else if headers.get("transfer-encoding").is_some() {
+            if !is_chunked(&headers) {
+                return Err(anyhow!("Unsupported Transfer-Encoding"));
+            }
+            read_chunked_body(reader).await?
+        } else if let Some(length) = content_length(&headers)? {
+            if length > MAX_BODY_SIZE {
+                return Err(body_too_large());
+            }
+            // Grow the buffer as data arrives instead of trusting the announced length
+            let mut body = Vec::new();
+            (&mut *reader)
+                .take(length as u64)
+                .read_to_end(&mut body)
+                .await?;
+            if body.len() < length {
+                return Err(anyhow!(
+                    "Connection closed after {} of {} body bytes",
+                    body.len(),
+                    length
+                ));
+            }
+            body
+        } else {
+            // Close-delimited body, the connection is used up
+            keep_alive = false;
+            let mut body = Vec::new();
+            let limit = MAX_BODY_SIZE as u64 + 1;
+            (&mut *reader).take(limit).read_to_end(&mut body).await?;
+            if body.len() > MAX_BODY_SIZE {
+                return Err(body_too_large());
+            }
+            body
+        };
+
+        let response = HttpResponse {
+            status,
+            headers,
+            body,
+        };
+        return Ok((response, keep_alive && status != 101));
+    }
+}
+
+/// Read the status line and headers, up to and including the empty line
+pub(crate) async fn read_head<R: AsyncBufRead + Unpin>(reader: &mut R) -> Result<Vec<u8>> {
+    let mut head = Vec::new();
+    loop {
+        // Stop one byte past the limit, even inside an endless line
+        let limit = (MAX_HEAD_SIZE + 1 - head.len()) as u64;
+        let n = (&mut *reader)
+            .take(limit)
+            .read_until(b'\n', &mut head)
+            .await?;
+        if n == 0 {
+            return Err(anyhow!("Connection closed before response headers ended"));
+        }
+        if head.len() > MAX_HEAD_SIZE {
+            return Err(anyhow!("HTTP response headers too large"));
+        }
+        if head.ends_with(b"\r\n\r\n") || head.ends_with(b"\n\n") || head == b"\r\n" {
+            // Tolerate stray empty lines before the status line
+            if head.iter().all(|b| *b == b'\r' || *b == b'\n') {
+                head.clear();
+                continue;
+            }
+            return Ok(head);
+        }
+    }
+}
+
+/// Parse the minor HTTP version, status code and headers of a complete response head
+pub(crate) fn parse_head(head: &[u8]) -> Result<(u8, u16, HeaderMap)> {
+    let mut raw_headers = [httparse::EMPTY_HEADER; MAX_HEADERS];
+    let mut response = httparse::Response::new(&mut raw_headers);
+    match response.parse(head)? {
+        httparse::Status::Complete(_) => {}
+        httparse::Status::Partial => return Err(anyhow!("Incomplete HTTP response headers")),
+    }
+
+    let status = response
+        .code
+        .ok_or_else(|| anyhow!("Missing HTTP status code"))?;
+    let headers = response
+        .headers
+        .iter()
+        .map(|h| (h.name, String::from_utf8_lossy(h.value).into_owned()))
+        .collect();
+    Ok((response.version.unwrap_or(0), status, headers))
+}
+
+/// The request line and headers of a request received by the bridge
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct RequestHead {
+    pub method: String,
+    /// Request target as sent: origin-form path, absolute URL or CONNECT authority
+    pub target: String,
+    /// Minor HTTP version, `0` or `1`
+    pub version: u8,
+    pub headers: HeaderMap,
+}
+
+impl RequestHead {
+    /// Protocol version as written in a status line
+    pub fn http_version(&self) -> &'static str {
+        if self.version == 0 {
+            "HTTP/1.0"
+        } else {
+            "HTTP/1.1"
+        }
+    }
+}
+
+/// A request head that must be refused, with the response status to send
+#[derive(Debug, Clone, PartialEq, Eq, thiserror::Error)]
+pub(crate) enum RequestHeadError {
+    /// Malformed or ambiguous request, answered with `400 Bad Request`
+    #[error("{0}")]
+    Invalid(String),
+    /// Request line and headers over the size or count limit, answered with
+    /// `431 Request Header Fields Too Large`
+    #[error(
+        "Request headers exceed {MAX_REQUEST_HEAD_SIZE} bytes or {MAX_REQUEST_HEADERS} fields"
+    )]
+    TooLarge,
+}
+
+impl RequestHeadError {
+    /// HTTP status code and reason phrase to answer with
+    pub fn http_status(&self) -> (u16, &'static str) {
+        match self {
+            RequestHeadError::Invalid(_) => (400, "Bad Request"),
+            RequestHeadError::TooLarge => (431, "Request Header Fields Too Large"),
+        }
+    }
+}
+
+/// Read and validate the next request head, consuming exactly its bytes, so
+/// pipelined requests and bodies stay in `reader`. The head is parsed as it
+/// arrives and never buffered beyond the size limit. Returns `None` if the
+/// connection closed before a request started.
+///
+/// Refused heads fail with a [`RequestHeadError`]: obsolete line folding,
+/// whitespace before a header colon, invalid characters, more than one `Host`,
+/// a missing `Host` in HTTP/1.1 (except for `CONNECT`), and `Transfer-Encoding`
+/// in HTTP/1.0, which would leave the body framing open to interpretation.
+pub(crate) async fn read_request_head<R: AsyncBufRead + Unpin>(
+    reader: &mut R,
+) -> Result<Option<RequestHead>> {
+    let mut head = Vec::new();
+    loop {
+        let chunk = reader.fill_buf().await?;
+        if chunk.is_empty() {
+            // Empty lines before a request are ignored, also at the end of a connection
+            if head.iter().all(|b| *b == b'\r' || *b == b'\n') {
+                return Ok(None);
+            }
+            return Err(anyhow!(
+                "Client closed connection before sending complete request"
+            ));
+        }
+
+        // Never look at more than one byte past the limit
+        let previous = head.len();
+        let take = chunk.len().min(MAX_REQUEST_HEAD_SIZE + 1 - previous);
+        head.extend_from_slice(&chunk[..take]);
+
+        let mut raw_headers = [httparse::EMPTY_HEADER; MAX_REQUEST_HEADERS];
+        let mut request = httparse::Request::new(&mut raw_headers);
+        match request.parse(&head) {
+            Ok(httparse::Status::Complete(len)) => {
+                // Leave anything after the head in the reader
+                reader.consume(len - previous);
+                let head = RequestHead {
+                    method: request.method.unwrap_or_default().to_string(),
+                    target: request.path.unwrap_or_default().to_string(),
+                    version: request.version.unwrap_or(1),
+                    headers: request
+                        .headers
+                        .iter()
+                        .map(|h| (h.name, String::from_utf8_lossy(h.value).into_owned()))
+                        .collect(),
+                };
+                validate_request_head(&head)?;
+                return Ok(Some(head));
+            }
+            Ok(httparse::Status::Partial) => {
+                reader.consume(take);
+                if head.len() > MAX_REQUEST_HEAD_SIZE {
+                    return Err(RequestHeadError::TooLarge.into());
+                }
+            }
+            Err(httparse::Error::TooManyHeaders) => return Err(RequestHeadError::TooLarge.into()),
+            Err(e) => {
+                let message = if is_obs_fold(&head) {
+                    "Obsolete line folding in request headers".to_string()
+                } else {
+                    format!("Malformed request head: {}", e)
+                };
+                return Err(RequestHeadError::Invalid(message).into());
+            }
+        }
+    }
+}
+
+/// Whether a header line starts with whitespace, continuing the previous one
+fn is_obs_fold(head: &[u8]) -> bool {
+    head.windows(2)
+        .any(|w| w[0] == b'\n' && (w[1] == b' ' || w[1] == b'\t'))
+}
+
+/// Checks on a complete request head that the parser leaves to the caller
+fn validate_request_head(head: &RequestHead) -> Result<(), RequestHeadError> {
+    let invalid = |message: &str| Err(RequestHeadError::Invalid(message.to_string()));
+    match head.headers.get_all("host").count() {
+        0 if head.version == 1 && head.method != "CONNECT" => {
+            return invalid("Missing Host header");
+        }
+        0 | 1 => {}
+        _ => return invalid("Multiple Host headers"),
+    }
+    if head.version == 0 && head.headers.contains("transfer-encoding") {
+        return invalid("Transfer-Encoding in an HTTP/1.0 request");
+    }
+    Ok(())
+}
+
+/// Parse `Content-Length`, rejecting conflicting values
+pub(crate) fn content_length(headers: &HeaderMap) -> Result<Option<usize>> {
+    let mut length = None;
+    for value in headers.get_all("content-length") {
+        let parsed: usize = value
+            .trim()
+            .parse()
+            .map_err(|_| anyhow!("Invalid Content-Length: {}", value))?;
+        if length.is_some_and(|l| l != parsed) {
+            return Err(anyhow!("Conflicting Content-Length headers"));
+        }
+        length = Some(parsed);
+    }
+    Ok(length)
+}
+
+/// Whether a request with this method may be sent again after a failed attempt
+pub(crate) fn is_idempotent(method: &str) -> bool {
+    matches!(method, "GET" | "HEAD" | "OPTIONS")
+}
+
+/// Whether `chunked` is the final transfer coding, the one that frames the body
+pub(crate) fn is_chunked(headers: &HeaderMap) -> bool {
+    headers
+        .get_all("transfer-encoding")
+        .flat_map(|v| v.split(','))
+        .map(str::trim)
+        .filter(|coding| !coding.is_empty())
+        .last()
+        .is_some_and(|coding| coding.eq_ignore_ascii_case("chunked"))
+}
+
+/// Size from a chunk-size line: 1*HEXDIG, optionally followed by chunk
+/// extensions after `;`
+pub(crate) fn parse_chunk_size(line: &str) -> Result<u64> {
+    let size = line
+        .trim_end_matches(['\r', '\n'])
+        .split(';')
+        .next()
+        .unwrap_or("")
+        .trim_end_matches([' ', '\t']);
+    if size.is_empty() || !size.bytes().all(|b| b.is_ascii_hexdigit()) {
+        return Err(anyhow!("Invalid chunk size: {:?}", size));
+    }
+    u64::from_str_radix(size, 16).map_err(|_| anyhow!("Chunk size too large: {:?}", size))
+}
+
+/// Read a `Transfer-Encoding: chunked` body, discarding any trailers
+async fn read_chunked_body<R: AsyncBufRead + Unpin>(reader: &mut R) -> Result<Vec<u8>> {
+    let mut body = Vec::new();
+    let mut line = String::new();
+    loop {
+        if read_chunk_line(reader, &mut line).await? == 0 {
+            return Err(anyhow!("Connection closed inside chunked body"));
+        }
+        // Chunk extensions after ';' are ignored
+        let size = usize::try_from(parse_chunk_size(&line)?).map_err(|_| body_too_large())?;
+
+        if size == 0 {
+            // Trailer section ends with an empty line
+            let mut trailers = 0;
+            loop {
+                let n = read_chunk_line(reader, &mut line).await?;
+                if n == 0 || line.trim().is_empty() {
+                    return Ok(body);
+                }
+                trailers += n;
+                if trailers > MAX_HEAD_SIZE {
+                    return Err(anyhow!("HTTP response trailers too large"));
+                }
+            }
+        }
+
+        if body
+            .len()
+            .checked_add(size)
+            .is_none_or(|total| total > MAX_BODY_SIZE)
+        {
+            return Err(body_too_large());
+        }
+        let read = (&mut *reader)
+            .take(size as u64)
+            .read_to_end(&mut body)
+            .await?;
+        if read < size {
+            return Err(anyhow!("Connection closed inside chunked body"));
+        }
+
+        read_chunk_line(reader, &mut line).await?;
+        if !line.trim().is_empty() {
+            return Err(anyhow!("Missing CRLF after chunk data"));
+        }
+    }
+}
+
+/// Read one chunk size or trailer line into `line`, refusing lines longer
+/// than a response head may be
+async fn read_chunk_line<R: AsyncBufRead + Unpin>(
+    reader: &mut R,
+    line: &mut String,
+) -> Result<usize> {
+    line.clear();
+    let limit = MAX_HEAD_SIZE as u64;
+    let n = (&mut *reader).take(limit).read_line(line).await?;
+    if n as u64 == limit && !line.ends_with('\n') {
+        return Err(anyhow!("Chunk line too long"));
+    }
+    Ok(n)
+}
+
+fn body_too_large() -> anyhow::Error {
+    anyhow!("HTTP response body exceeds {} bytes", MAX_BODY_SIZE)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    async fn parse(raw: &[u8], is_head: bool) -> Result<(HttpResponse, bool, Vec<u8>)> {
+        let mut reader = raw;
+        let (response, keep_alive) = read_response_keep_alive(&mut reader, is_head).await?;
+        Ok((response, keep_alive, reader.to_vec()))
+    }
+
+    #[tokio::test]
+    async fn content_length_body() {
+        let raw = b"HTTP/1.1 200 OK\r\nContent-Length: 5\r\n\r\nhelloNEXT";
+        let (response, keep_alive, rest) = parse(raw, false).await.unwrap();
+        assert_eq!(response.status, 200);
+        assert_eq!(response.body, b"hello");
+        assert!(keep_alive);
+        assert_eq!(rest, b"NEXT");
+    }
+
+    #[tokio::test]
+    async fn short_content_length_body_fails() {
+        let raw = b"HTTP/1.1 200 OK\r\nContent-Length: 10\r\n\r\nhello";
+        assert!(parse(raw, false).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn huge_content_length_is_refused() {
+        let raw = b"HTTP/1.1 200 OK\r\nContent-Length: 99999999999999\r\n\r\nhello";
+        let err = parse(raw, false).await.unwrap_err();
+        assert!(err.to_string().contains("exceeds"), "{}", err);
+    }
+
+    #[tokio::test]
+    async fn conflicting_content_length_fails() {
+        let raw = b"HTTP/1.1 200 OK\r\nContent-Length: 5\r\nContent-Length: 6\r\n\r\nhello!";
+        assert!(parse(raw, false).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn chunked_body_with_extensions_and_trailers() {
+        let raw = b"HTTP/1.1 200 OK\r\nTransfer-Encoding: chunked\r\n\r\n\
+            5;name=value\r\nhello\r\n\
+            6\r\n world\r\n\
+            0;last\r\nX-Checksum: abc\r\nX-Other: def\r\n\r\nNEXT";
+        let (response, keep_alive, rest) = parse(raw, false).await.unwrap();
+        assert_eq!(response.body, b"hello world");
+        assert!(keep_alive);
+        assert_eq!(rest, b"NEXT");
+    }
+
+    #[tokio::test]
+    async fn huge_chunk_size_is_refused() {
+        for size in ["ffffffffffffffff", "4000001"] {
+            let raw = format!(
+                "HTTP/1.1 200 OK\r\nTransfer-Encoding: chunked\r\n\r\n1\r\na\r\n{}\r\nb\r\n0\r\n\r\n",
+                size
+            );
+            let err = parse(raw.as_bytes(), false).await.unwrap_err();
+            assert!(err.to_string().contains("exceeds"), "{}", err);
+        }
+    }
+
+    #[tokio::test]
+    async fn invalid_chunked_bodies_fail() {
+        for body in [
+            "zz\r\nhello\r\n0\r\n\r\n",
+            "+5\r\nhello\r\n0\r\n\r\n",
+            "-0\r\n\r\n",
+            " 5\r\nhello\r\n0\r\n\r\n",
+            "0x5\r\nhello\r\n0\r\n\r\n",
+            ";ext\r\n\r\n",
+            "1ffffffffffffffff\r\n",
+            "5\r\nhelloXX0\r\n\r\n",
+            "5\r\nhel",
+            "5\r\nhello\r\n",
+        ] {
+            let raw = format!(
+                "HTTP/1.1 200 OK\r\nTransfer-Encoding: chunked\r\n\r\n{}",
+                body
+            );
+            assert!(parse(raw.as_bytes(), false).await.is_err(), "{:?}", body);
+        }
+    }
+
+    #[tokio::test]
+    async fn non_chunked_transfer_encoding_fails() {
+        for coding in ["gzip", "chunked, gzip"] {
+            let raw = format!(
+                "HTTP/1.1 200 OK\r\nTransfer-Encoding: {}\r\n\r\n5\r\nhello\r\n0\r\n\r\n",
+                coding
+            );
+            assert!(parse(raw.as_bytes(), false).await.is_err(), "{}", coding);
+        }
+    }
+
+    #[test]
+    fn chunk_size_lines() {
+        let cases: &[(&str, Option<u64>)] = &[
+            ("0\r\n", Some(0)),
+            ("1a\r\n", Some(26)),
+            ("00FF\r\n", Some(255)),
+            ("5;name=value\r\n", Some(5)),
+            ("5 ;name\r\n", Some(5)),
+            ("ffffffffffffffff\r\n", Some(u64::MAX)),
+            ("\r\n", None),
+            ("+5\r\n", None),
+            ("-5\r\n", None),
+            (" 5\r\n", None),
+            ("0x5\r\n", None),
+            ("5 5\r\n", None),
+            ("g\r\n", None),
+            ("10000000000000000\r\n", None),
+        ];
+        for (line, expected) in cases {
+            assert_eq!(parse_chunk_size(line).ok(), *expected, "{:?}", line);
+        }
+    }
+
+    #[tokio::test]
+    async fn close_delimited_body() {
+        let raw = b"HTTP/1.1 200 OK\r\nContent-Type: text/plain\r\n\r\nuntil the end";
+        let (response, keep_alive, rest) = parse(raw, false).await.unwrap();
+        assert_eq!(response.body, b"until the end");
+        assert!(!keep_alive);
+        assert!(rest.is_empty());
+    }
+
+    #[tokio::test]
+    async fn responses_without_body() {
+        let raw = b"HTTP/1.1 200 OK\r\nContent-Length: 5\r\n\r\nNEXT";
+        let (response, keep_alive, rest) = parse(raw, true).await.unwrap();
+        assert!(response.body.is_empty());
+        assert!(keep_alive);
+        assert_eq!(rest, b"NEXT");
+
+        for status in ["204 No Content", "304 Not Modified"] {
+            let raw = format!("HTTP/1.1 {}\r\nContent-Length: 5\r\n\r\nNEXT", status);
+            let (response, keep_alive, rest) = parse(raw.as_bytes(), false).await.unwrap();
+            assert!(response.body.is_empty());
+            assert!(keep_alive);
+            assert_eq!(rest, b"NEXT");
+        }
+    }
+
+    #[tokio::test]
+    async fn interim_responses_are_skipped() {
+        let raw = b"HTTP/1.1 100 Continue\r\n\r\n\
+            HTTP/1.1 103 Early Hints\r\nLink: </style.css>\r\n\r\n\
+            HTTP/1.1 201 Created\r\nContent-Length: 2\r\n\r\nok";
+        let (response, _, _) = parse(raw, false).await.unwrap();
+        assert_eq!(response.status, 201);
+        assert_eq!(response.body, b"ok");
+        assert_eq!(response.headers.get("link"), None);
+    }
+
+    #[tokio::test]
+    async fn keep_alive_follows_version_and_connection() {
+        let cases: [(&[u8], bool); 3] = [
+            (
+                b"HTTP/1.1 200 OK\r\nConnection: close\r\nContent-Length: 0\r\n\r\n",
+                false,
+            ),
+            (b"HTTP/1.0 200 OK\r\nContent-Length: 0\r\n\r\n", false),
+            (
+                b"HTTP/1.0 200 OK\r\nConnection: keep-alive\r\nContent-Length: 0\r\n\r\n",
+                true,
+            ),
+        ];
+        for (raw, expected) in cases {
+            let (_, keep_alive, _) = parse(raw, false).await.unwrap();
+            assert_eq!(keep_alive, expected);
+        }
+    }
+
+    #[tokio::test]
+    async fn endless_header_line_is_refused() {
+        let mut raw = b"HTTP/1.1 200 OK\r\nX-Long: ".to_vec();
+        raw.resize(MAX_HEAD_SIZE * 2, b'a');
+        assert!(parse(&raw, false).await.is_err());
+    }
+
+    /// Parse a request head from `raw`, delivered `chunk` bytes per read;
+    /// returns the head and the bytes left in the reader
+    async fn parse_request(raw: &[u8], chunk: usize) -> Result<(Option<RequestHead>, Vec<u8>)> {
+        let mut reader = tokio::io::BufReader::with_capacity(chunk, raw);
+        let head = read_request_head(&mut reader).await?;
+        let mut rest = Vec::new();
+        reader.read_to_end(&mut rest).await?;
+        Ok((head, rest))
+    }
+
+    async fn request_error(raw: &[u8]) -> RequestHeadError {
+        let err = parse_request(raw, 8192).await.unwrap_err();
+        err.downcast::<RequestHeadError>()
+            .unwrap_or_else(|e| panic!("not a RequestHeadError: {}", e))
+    }
+
+    #[tokio::test]
+    async fn request_head_is_parsed() {
+        let raw = b"POST http://example.com/tx HTTP/1.1\r\nHost: example.com\r\n\
+            Content-Length: 4\r\nX-Two: a\r\nx-two: b\r\n\r\nbody";
+        let (head, rest) = parse_request(raw, 8192).await.unwrap();
+        let head = head.unwrap();
+        assert_eq!(head.method, "POST");
+        assert_eq!(head.target, "http://example.com/tx");
+        assert_eq!(head.http_version(), "HTTP/1.1");
+        assert_eq!(
+            head.headers.get_all("x-two").collect::<Vec<_>>(),
+            ["a", "b"]
+        );
+        assert_eq!(rest, b"body");
+    }
+
+    #[tokio::test]
+    async fn request_head_split_across_reads() {
+        let raw = b"GET /a HTTP/1.1\r\nHost: example.com\r\nAccept: */*\r\n\r\n";
+        let (expected, _) = parse_request(raw, 8192).await.unwrap();
+        for chunk in 1..raw.len() {
+            let (head, rest) = parse_request(raw, chunk).await.unwrap();
+            assert_eq!(head, expected, "chunk size {}", chunk);
+            assert!(rest.is_empty());
+        }
+    }
+
+    #[tokio::test]
+    async fn pipelined_requests_stay_in_reader() {
+        let first = b"GET /1 HTTP/1.1\r\nHost: a\r\n\r\n";
+        let second = b"GET /2 HTTP/1.1\r\nHost: a\r\n\r\n";
+        let raw = [&first[..], &second[..]].concat();
+        for chunk in [1, 7, 8192] {
+            let mut reader = tokio::io::BufReader::with_capacity(chunk, raw.as_slice());
+            let one = read_request_head(&mut reader).await.unwrap().unwrap();
+            let two = read_request_head(&mut reader).await.unwrap().unwrap();
+            assert_eq!((one.target.as_str(), two.target.as_str()), ("/1", "/2"));
+            assert!(read_request_head(&mut reader).await.unwrap().is_none());
+        }
+    }
+
+    #[tokio::test]
+    async fn request_head_end_of_stream() {
+        assert_eq!(parse_request(b"", 8192).await.unwrap(), (None, Vec::new()));
+        assert_eq!(parse_request(b"\r\n\r\n", 8192).await.unwrap().0, None);
+        // Empty lines before a request are skipped
+        let (head, _) = parse_request(b"\r\nGET / HTTP/1.1\r\nHost: a\r\n\r\n", 8192)
+            .await
+            .unwrap();
+        assert_eq!(head.unwrap().target, "/");
+        assert!(parse_request(b"GET / HTTP/1.1\r\nHost: a\r\n", 8192)
+            .await
+            .is_err());
+    }
+
+    #[tokio::test]
+    async fn requests_without_host() {
+        for raw in [
+            &b"CONNECT example.com:443 HTTP/1.1\r\n\r\n"[..],
+            b"GET http://example.com/ HTTP/1.0\r\n\r\n",
+        ] {
+            assert!(parse_request(raw, 8192).await.unwrap().0.is_some());
+        }
+    }
+
+    #[tokio::test]
+    async fn invalid_request_heads_are_rejected() {
+        for raw in [
+            // Obsolete line folding
+            &b"GET / HTTP/1.1\r\nHost: a\r\nX-Folded: one\r\n two\r\n\r\n"[..],
+            b"GET / HTTP/1.1\r\nHost: a\r\nX-Folded: one\r\n\ttwo\r\n\r\n",
+            // Whitespace before the colon
+            b"GET / HTTP/1.1\r\nHost : a\r\n\r\n",
+            b"GET / HTTP/1.1\r\nHost: a\r\nContent-Length\t: 5\r\n\r\nhello",
+            // Duplicate Host
+            b"GET / HTTP/1.1\r\nHost: a\r\nHost: b\r\n\r\n",
+            b"GET / HTTP/1.1\r\nHost: a\r\nhost: a\r\n\r\n",
+            // Missing Host in HTTP/1.1
+            b"GET / HTTP/1.1\r\nAccept: */*\r\n\r\n",
+            // Transfer-Encoding in HTTP/1.0
+            b"POST / HTTP/1.0\r\nHost: a\r\nTransfer-Encoding: chunked\r\n\r\n",
+            // Invalid characters and versions
+            b"GET / HTTP/1.1\r\nHo\x01st: a\r\n\r\n",
+            b"GET / HTTP/2.0\r\nHost: a\r\n\r\n",
+            b"G@T / HTTP/1.1\r\nHost: a\r\n\r\n",
+        ] {
+            let error = request_error(raw).await;
+            assert_eq!(
+                error.http_status().0,
+                400,
+                "{:?}",
+                String::from_utf8_lossy(raw)
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn oversized_request_heads_are_rejected() {
+        let mut many = b"GET / HTTP/1.1\r\nHost: a\r\n".to_vec();
+        for i in 0..MAX_REQUEST_HEADERS {
+            many.extend_from_slice(format!("X-{}: {}\r\n", i, i).as_bytes());
+        }
+        many.extend_from_slice(b"\r\n");
+        assert_eq!(request_error(&many).await, RequestHeadError::TooLarge);
+
+        let mut long = b"GET / HTTP/1.1\r\nHost: a\r\nX-Long: ".to_vec();
+        long.resize(MAX_REQUEST_HEAD_SIZE + 1, b'a');
+        long.extend_from_slice(b"\r\n\r\n");
+        assert_eq!(request_error(&long).await, RequestHeadError::TooLarge);
+        // Endless data without a line break
+        assert_eq!(
+            request_error(&vec![b'a'; MAX_REQUEST_HEAD_SIZE * 4]).await,
+            RequestHeadError::TooLarge
+        );
+    }
+
+    fn request_text(request: &HttpRequest) -> String {
+        String::from_utf8(request.to_bytes().unwrap()).unwrap()
+    }
+
+    #[test]
+    fn request_defaults_and_content_length() {
+        let text = request_text(&HttpRequest::post(
+            "http://example.com:8080/tx?x=1",
+            b"abc".to_vec(),
+        ));
+        assert_eq!(
+            text,
+            format!(
+                "POST /tx?x=1 HTTP/1.1\r\nHost: example.com:8080\r\nUser-Agent: {}\r\n\
+                 Accept: */*\r\nConnection: close\r\nContent-Length: 3\r\n\r\nabc",
+                COMMON_USER_AGENT
+            )
+        );
+
+        // Bodyless POST gets an explicit zero length, GET none
+        assert!(
+            request_text(&HttpRequest::new("post", "http://a/")).contains("Content-Length: 0\r\n")
+        );
+        assert!(!request_text(&HttpRequest::get("http://a")).contains("Content-Length"));
+        assert!(request_text(&HttpRequest::get("http://a"))
+            .starts_with("GET / HTTP/1.1\r\nHost: a\r\n"));
+    }
+
+    #[test]
+    fn caller_headers_replace_defaults() {
+        let request = HttpRequest::post("https://example.com/", b"abc".to_vec())
+            .with_header("host", "other.example")
+            .with_header("user-agent", "wallet/1.0")
+            .with_header("Accept", "application/json")
+            .with_header("connection", "keep-alive")
+            .with_header("Content-Length", "99")
+            .with_header("Transfer-Encoding", "chunked")
+            .with_header("Authorization", "Bearer token");
+        let text = request_text(&request);
+        let head = text.split("\r\n\r\n").next().unwrap();
+        let lines: Vec<_> = head.lines().skip(1).collect();
+        assert_eq!(
+            lines,
+            [
+                "Host: other.example",
+                "user-agent: wallet/1.0",
+                "Accept: application/json",
+                "connection: keep-alive",
+                "Authorization: Bearer token",
+                "Content-Length: 3",
+            ]
+        );
+    }
+
+    #[test]
+    fn invalid_requests_are_refused() {
+        let invalid = [
+            HttpRequest::new("GET /x", "http://a/"),
+            HttpRequest::new("", "http://a/"),
+            HttpRequest::new("G\r\nET", "http://a/"),
+            HttpRequest::get("http://a/").with_header("X-Bad\r\nInjected", "1"),
+            HttpRequest::get("http://a/").with_header("X Bad", "1"),
+            HttpRequest::get("http://a/").with_header("", "1"),
+            HttpRequest::get("http://a/").with_header("X-Bad", "1\r\nInjected: 1"),
+            HttpRequest::get("http://a/").with_header("X-Bad", "1\nInjected: 1"),
+            HttpRequest::get("http://a/").with_header("X-Bad", "1\0"),
+            HttpRequest::get("not a url"),
+            HttpRequest::get("data:text/plain,hello"),
+        ];
+        for request in invalid {
+            assert!(request.to_bytes().is_err(), "{:?}", request);
+        }
+    }
+
+    #[test]
+    fn header_policy_normalizes_set_order_and_user_agent() {
+        let headers: HeaderMap = [
+            ("content-length", "2"),
+            ("Cookie", "a=b"),
+            ("user-agent", "wallet/1.2.3"),
+            ("Accept-Language", "de-CH"),
+            ("accept", "*/*"),
+            ("host", "example.com"),
+        ]
+        .into_iter()
+        .collect();
+        let normalized = HeaderPolicy::default().apply(&headers);
+        let names: Vec<_> = normalized.iter().map(|(name, _)| name).collect();
+        assert_eq!(names, ["Host", "User-Agent", "Accept", "Content-Length"]);
+        assert_eq!(normalized.get("user-agent"), Some(COMMON_USER_AGENT));
+
+        // Host and the framing headers survive an allowlist without them
+        let policy = HeaderPolicy {
+            user_agent: None,
+            allowed_headers: vec!["Accept".to_string()],
+        };
+        let normalized = policy.apply(&headers);
+        let names: Vec<_> = normalized.iter().map(|(name, _)| name).collect();
+        assert_eq!(names, ["Host", "Accept", "Content-Length"]);
+    }
+
+    mod request_head_properties {
+        use super::*;
+        use proptest::prelude::*;
+
+        fn parse(raw: &[u8], chunk: usize) -> Result<(Option<RequestHead>, Vec<u8>)> {
+            // Reading from a slice never waits, so no runtime is needed
+            futures::executor::block_on(parse_request(raw, chunk))
+        }
+
+        proptest! {
+            /// Arbitrary input never panics, and a parsed head consumes exactly
+            /// the bytes of a complete request head
+            #[test]
+            fn arbitrary_bytes(raw in proptest::collection::vec(any::<u8>(), 0..2048),
+                               chunk in 1usize..64) {
+                if let Ok((Some(_), rest)) = parse(&raw, chunk) {
+                    let consumed = &raw[..raw.len() - rest.len()];
+                    prop_assert_eq!(&raw[consumed.len()..], rest.as_slice());
+                    let mut headers = [httparse::EMPTY_HEADER; MAX_REQUEST_HEADERS];
+                    let parsed = httparse::Request::new(&mut headers).parse(consumed);
+                    prop_assert_eq!(parsed.ok(), Some(httparse::Status::Complete(consumed.len())));
+                }
+            }
+
+            /// A valid head followed by anything leaves exactly the tail in the reader
+            #[test]
+            fn valid_head_then_tail(path in "/[a-z0-9/]{0,20}",
+                                    names in proptest::collection::vec("X-[A-Za-z]{1,8}", 0..8),
+                                    tail in proptest::collection::vec(any::<u8>(), 0..256),
+                                    chunk in 1usize..64) {
+                let mut raw = format!("GET {} HTTP/1.1\r\nHost: example.com\r\n", path);
+                for (i, name) in names.iter().enumerate() {
+                    raw.push_str(&format!("{}: {}\r\n", name, i));
+                }
+                raw.push_str("\r\n");
+                let mut raw = raw.into_bytes();
+                raw.extend_from_slice(&tail);
+
+                let (head, rest) = parse(&raw, chunk).unwrap();
+                let head = head.unwrap();
+                prop_assert_eq!(head.target, path);
+                prop_assert_eq!(head.headers.len(), names.len() + 1);
+                prop_assert_eq!(rest, tail);
+            }
+        }
+    }
+}
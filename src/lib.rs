@@ -0,0 +1,10 @@
+//! Tor-enabled HTTP building blocks for an async-minreq based Esplora client.
+
+pub mod esplora;
+pub mod http;
+pub mod http_socks_bridge;
+pub mod pool;
+pub mod socks_server;
+pub mod tls;
+pub mod tor_integration;
+pub mod transport;
@@ -1,18 +1,23 @@
 use anyhow::{anyhow, Result};
+use futures::StreamExt;
 use log::{error, info, warn};
+use tokio::sync::oneshot;
 use tokio::task;
 use url::Url;
 
-mod http_socks_bridge;
-mod tor_integration;
-
-use http_socks_bridge::{start_http_socks_bridge, BridgeConfig};
-use tor_integration::{create_tor_client, fetch_via_arti};
+use bdk_arti_esplora::esplora::EsploraClient;
+use bdk_arti_esplora::http::HttpRequest;
+use bdk_arti_esplora::http_socks_bridge::{BridgeConfig, SocksAuth};
+use bdk_arti_esplora::tor_integration::{
+    bootstrap_progress_events, bootstrap_tor_client, create_unbootstrapped_tor_client, TorSettings,
+};
+use bdk_arti_esplora::transport::{HttpTransport, TransportConfig};
 
 const TEST_URL: &str = "http://check.torproject.org/api/ip";
 const ONION_TEST_URL: &str =
     "http://2gzyxa5ihm7nsggfxnu52rck2vv4rvmdlkiu3zzui5du4xyclen53wid.onion/";
 const TOR_SOCKS_PROXY: &str = "socks5://127.0.0.1:9050";
+const ESPLORA_URL: &str = "https://blockstream.info/api";
 
 // Add simple logging to help debug any Tor issues
 fn setup_logging() {
@@ -47,6 +52,11 @@ async fn main() -> Result<()> {
         error!("HTTP-SOCKS bridge request failed: {}", e);
     }
 
+    // 5. Esplora API calls via HTTP-SOCKS bridge
+    if let Err(e) = test_esplora_client().await {
+        error!("Esplora client request failed: {}", e);
+    }
+
     Ok(())
 }
 
@@ -54,23 +64,13 @@ async fn main() -> Result<()> {
 async fn test_direct_http() -> Result<()> {
     info!("\n1. Testing direct HTTP request with minreq (async wrapper)...");
 
-    // Since minreq is synchronous, we use tokio's spawn_blocking
+    // The direct transport runs minreq on tokio's blocking pool
     // to avoid blocking the async runtime
-    let response =
-        task::spawn_blocking(move || minreq::get(TEST_URL).with_timeout(10).send()).await??;
-
-    if response.status_code >= 200 && response.status_code < 300 {
-        let body = response.as_str()?;
-        info!("✅ Direct HTTP request successful");
-        info!("Status: {}", response.status_code);
-        info!("Response: {}", body);
-        Ok(())
-    } else {
-        Err(anyhow!(
-            "Request failed with status code: {}",
-            response.status_code
-        ))
-    }
+    let transport = TransportConfig::Direct.build().await?;
+    let body = fetch_with_transport(transport.as_ref(), TEST_URL).await?;
+    info!("✅ Direct HTTP request successful");
+    info!("Response: {}", body);
+    Ok(())
 }
 
 /// Test HTTP request via SOCKS proxy (Tor)
@@ -109,19 +109,32 @@ async fn test_socks_proxy() -> Result<()> {
 async fn test_arti_integration() -> Result<()> {
     info!("\n3. Testing HTTP request via Arti Tor client...");
 
-    // Create and bootstrap the Tor client
-    let tor_client = create_tor_client().await?;
+    // Create the Tor client and bootstrap it while reporting progress
+    let tor_client = create_unbootstrapped_tor_client(&TorSettings::default())?;
+    let mut progress = bootstrap_progress_events(&tor_client);
+    tokio::spawn(async move {
+        while let Some(p) = progress.next().await {
+            info!("   Bootstrap {}%: {}", p.percent, p.description);
+            if p.ready {
+                break;
+            }
+        }
+    });
+    // Keep the sender alive; sending on it (or dropping it) cancels the bootstrap
+    let (_cancel_tx, cancel_rx) = oneshot::channel();
+    bootstrap_tor_client(&tor_client, cancel_rx).await?;
     info!("   Tor client bootstrapped successfully");
+    let transport = TransportConfig::Arti(Box::new(tor_client)).build().await?;
 
     // Try a regular HTTP URL first
     info!("   Fetching regular HTTP URL via Tor: {}", TEST_URL);
-    let response = fetch_via_arti(&tor_client, TEST_URL).await?;
+    let response = fetch_with_transport(transport.as_ref(), TEST_URL).await?;
     info!("✅ Regular HTTP request via Tor successful");
     info!("Response: {}", response);
 
     // Try an onion service
     info!("   Fetching onion service: {}", ONION_TEST_URL);
-    match fetch_via_arti(&tor_client, ONION_TEST_URL).await {
+    match fetch_with_transport(transport.as_ref(), ONION_TEST_URL).await {
         Ok(response) => {
             info!("✅ Onion service request successful");
             info!("Response length: {} bytes", response.len());
@@ -153,48 +166,60 @@ async fn test_http_socks_bridge() -> Result<()> {
 
     // Start the HTTP-SOCKS bridge with default configuration
     // (127.0.0.1:8118 forwarding to 127.0.0.1:9050)
-    let config = BridgeConfig::default();
-    let (bridge_addr, _shutdown_tx) = start_http_socks_bridge(config).await?;
-
-    info!("   HTTP-SOCKS bridge started on {}", bridge_addr);
+    let transport = TransportConfig::Bridge(Box::default()).build().await?;
     info!("   Using bridge to access: {}", TEST_URL);
 
-    // Create the HTTP proxy URL that minreq will connect to
-    let http_proxy_url = format!("http://{}", bridge_addr);
+    let body = fetch_with_transport(transport.as_ref(), TEST_URL).await?;
+    info!("✅ HTTP-SOCKS bridge request successful");
+    info!("Response: {}", body);
 
-    // Build the request with HTTP proxy - using tokio's spawn_blocking
-    // since minreq is synchronous
-    let proxy_url = http_proxy_url.clone();
-    let response = task::spawn_blocking(move || {
-        minreq::get(TEST_URL)
-            .with_timeout(20) // Longer timeout for Tor
-            .with_proxy(minreq::Proxy::new(proxy_url.as_str())?)
-            .send()
-    })
-    .await??;
+    // Verify we're going through Tor by checking the response from check.torproject.org
+    if body.contains("\"IsTor\":true") {
+        info!("✅ Confirmed request went through Tor!");
+    } else {
+        warn!("⚠️ Request did not go through Tor");
+    }
 
-    if response.status_code >= 200 && response.status_code < 300 {
-        let body = response.as_str()?;
-        info!("✅ HTTP-SOCKS bridge request successful");
-        info!("Status: {}", response.status_code);
-        info!("Response: {}", body);
+    // Note: The bridge is shut down when the transport is dropped
+    Ok(())
+}
 
-        // Verify we're going through Tor by checking the response from check.torproject.org
-        if body.contains("\"IsTor\":true") {
-            info!("✅ Confirmed request went through Tor!");
-        } else {
-            warn!("⚠️ Request did not go through Tor");
-        }
+/// Test the typed Esplora client over the HTTP-SOCKS bridge
+async fn test_esplora_client() -> Result<()> {
+    info!("\n5. Testing Esplora client via HTTP-SOCKS bridge...");
 
-        Ok(())
+    // Bind to an ephemeral port so we don't clash with the bridge from test 4
+    let config = BridgeConfig {
+        http_bind_addr: "127.0.0.1:0".parse()?,
+        // Pass isolation groups on to Tor as SOCKS credentials
+        socks_auth: SocksAuth::FromProxyAuthorization,
+        ..BridgeConfig::default()
+    };
+    let transport = TransportConfig::Bridge(Box::new(config)).build().await?;
+
+    let client = EsploraClient::new(ESPLORA_URL, transport);
+    info!("   Using Esplora instance: {}", client.url());
+
+    let height = client.get_height().await?;
+    info!("✅ Tip height: {}", height);
+
+    let fees = client.get_fee_estimates().await?;
+    info!("✅ Fee estimate for 6 blocks: {:?} sat/vB", fees.get(&6));
+
+    Ok(())
+}
+
+/// Fetch a URL through any transport and return the body as text
+async fn fetch_with_transport(transport: &dyn HttpTransport, url: &str) -> Result<String> {
+    let response = transport.send(HttpRequest::get(url)).await?;
+    info!("Status: {}", response.status);
+
+    if response.is_success() {
+        Ok(response.text()?.to_string())
     } else {
         Err(anyhow!(
             "Request failed with status code: {}",
-            response.status_code
+            response.status
         ))
     }
-
-    // Note: We're not shutting down the bridge server here to allow
-    // additional requests during the program's lifetime if needed.
-    // The server will be shut down when the program exits.
 }
@@ -0,0 +1,377 @@
+use anyhow::{anyhow, Result};
+use arti_client::TorClient;
+use futures::FutureExt;
+use log::debug;
+use std::collections::HashMap;
+use std::fmt;
+use std::hash::Hash;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::io::{AsyncBufReadExt, BufReader};
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+use url::Url;
+
+use crate::http::{is_idempotent, HeaderPolicy, HttpRequest, HttpResponse, HttpStream, Isolation};
+use crate::tor_integration::{exchange, open_http_stream, IsolatedClients};
+
+/// Limits for the keep-alive connection pool
+#[derive(Debug, Clone)]
+pub struct PoolConfig {
+    /// How long an unused connection is kept before it is closed
+    pub idle_timeout: Duration,
+    /// Maximum number of requests in flight at once per host and isolation
+    /// group; idle connections are capped by `max_idle_per_host`.
+    /// Uniquely isolated requests are not counted.
+    pub max_per_host: usize,
+    /// Maximum number of idle connections kept per host and isolation group
+    pub max_idle_per_host: usize,
+    /// Header normalization for every request (`None` sends headers as given)
+    pub header_policy: Option<HeaderPolicy>,
+}
+
+impl Default for PoolConfig {
+    fn default() -> Self {
+        Self {
+            idle_timeout: Duration::from_secs(60),
+            max_per_host: 6,
+            max_idle_per_host: 4,
+            header_policy: Some(HeaderPolicy::default()),
+        }
+    }
+}
+
+pub(crate) type Connection = BufReader<Box<dyn HttpStream>>;
+
+/// A connection waiting in the pool
+struct IdleConnection {
+    conn: Connection,
+    idle_since: Instant,
+}
+
+/// Idle keep-alive connections by key (origin, isolation, ...), shared by
+/// the Arti pool and the bridge's upstream connections
+pub(crate) struct IdleConnections<K> {
+    idle_timeout: Duration,
+    max_idle_per_key: usize,
+    idle: Mutex<HashMap<K, Vec<IdleConnection>>>,
+}
+
+impl<K: Eq + Hash + fmt::Debug> IdleConnections<K> {
+    /// Keep up to `max_idle_per_key` connections per key for `idle_timeout`
+    pub fn new(idle_timeout: Duration, max_idle_per_key: usize) -> Self {
+        Self {
+            idle_timeout,
+            max_idle_per_key,
+            idle: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Take the most recently used live connection, dropping expired or stale ones
+    pub fn take(&self, key: &K) -> Option<Connection> {
+        let mut idle = self.idle.lock().unwrap();
+        self.sweep(&mut idle);
+        let connections = idle.get_mut(key)?;
+        while let Some(mut connection) = connections.pop() {
+            if connection.idle_since.elapsed() >= self.idle_timeout {
+                debug!("Dropping expired connection to {:?}", key);
+                continue;
+            }
+            if !is_alive(&mut connection.conn) {
+                debug!("Dropping stale connection to {:?}", key);
+                continue;
+            }
+            return Some(connection.conn);
+        }
+        idle.remove(key);
+        None
+    }
+
+    /// Keep a connection for later requests with the same key, once its response has been read
+    pub fn put(&self, key: K, conn: Connection) {
+        if self.max_idle_per_key == 0 {
+            return;
+        }
+        let mut idle = self.idle.lock().unwrap();
+        self.sweep(&mut idle);
+        let connections = idle.entry(key).or_default();
+        if connections.len() >= self.max_idle_per_key {
+            // Oldest connections are at the front
+            connections.remove(0);
+        }
+        connections.push(IdleConnection {
+            conn,
+            idle_since: Instant::now(),
+        });
+    }
+
+    /// Close the idle connections of all keys matching `f`
+    pub fn remove_where(&self, mut f: impl FnMut(&K) -> bool) {
+        let mut idle = self.idle.lock().unwrap();
+        idle.retain(|key, _| !f(key));
+    }
+
+    /// Number of idle connections for all keys
+    pub fn len(&self) -> usize {
+        let idle = self.idle.lock().unwrap();
+        idle.values().map(Vec::len).sum()
+    }
+
+    /// Close expired connections of every key, so keys that are never
+    /// requested again don't keep theirs open
+    fn sweep(&self, idle: &mut HashMap<K, Vec<IdleConnection>>) {
+        idle.retain(|_, connections| {
+            connections.retain(|c| c.idle_since.elapsed() < self.idle_timeout);
+            !connections.is_empty()
+        });
+    }
+}
+
+/// Per-host pool of persistent HTTP/1.1 connections over Arti streams.
+/// Connections are only reused within the same isolation group.
+pub struct ArtiConnectionPool {
+    clients: IsolatedClients,
+    config: PoolConfig,
+    /// Caps the number of connections in use at the same time, per pool key.
+    /// Only keys with requests in flight or waiting have an entry.
+    limits: Mutex<HashMap<String, Arc<Semaphore>>>,
+    idle: IdleConnections<String>,
+}
+
+impl ArtiConnectionPool {
+    pub fn new(tor_client: TorClient<tor_rtcompat::PreferredRuntime>, config: PoolConfig) -> Self {
+        Self {
+            clients: IsolatedClients::new(tor_client),
+            idle: IdleConnections::new(config.idle_timeout, config.max_idle_per_host),
+            config,
+            limits: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Send a request, reusing an idle connection to the same host if one is alive
+    pub async fn send(&self, request: &HttpRequest) -> Result<HttpResponse> {
+        let url = Url::parse(&request.url)?;
+
+        // Uniquely isolated requests get a fresh circuit and are never pooled
+        if request.isolation == Isolation::Unique {
+            let tor_client = self.clients.unique();
+            let mut conn = BufReader::new(open_http_stream(&tor_client, &url).await?);
+            let is_head = request.method == "HEAD";
+            let bytes = request.to_bytes_with_policy(self.config.header_policy.as_ref())?;
+            let (response, _) = exchange(&mut conn, &bytes, is_head).await?;
+            return Ok(response);
+        }
+        let key = pool_key(&url, &request.isolation)?;
+
+        // Wait for a free slot for this host
+        let _slot = self.host_slot(&key).await?;
+
+        // Ask the server to keep the connection open unless the caller opted out
+        let mut request = request.clone();
+        if !request.headers.contains("connection") {
+            request.headers.insert("Connection", "keep-alive");
+        }
+        let bytes = request.to_bytes_with_policy(self.config.header_policy.as_ref())?;
+        let is_head = request.method == "HEAD";
+        let idempotent = is_idempotent(&request.method);
+
+        // Try pooled connections first. The server may have closed one just as
+        // we picked it up, so idempotent requests fall through to the next one.
+        while let Some(mut conn) = self.idle.take(&key) {
+            debug!("Reusing pooled connection to {}", key);
+            match exchange(&mut conn, &bytes, is_head).await {
+                Ok((response, keep_alive)) => {
+                    if keep_alive {
+                        self.idle.put(key.clone(), conn);
+                    }
+                    return Ok(response);
+                }
+                Err(e) if idempotent => {
+                    debug!("Pooled connection to {} failed, retrying: {}", key, e);
+                }
+                Err(e) => return Err(e),
+            }
+        }
+
+        debug!("Opening new pooled connection to {}", key);
+        let tor_client = self.clients.for_isolation(&request.isolation);
+        let mut conn = BufReader::new(open_http_stream(&tor_client, &url).await?);
+        let (response, keep_alive) = exchange(&mut conn, &bytes, is_head).await?;
+        if keep_alive {
+            self.idle.put(key.clone(), conn);
+        }
+        Ok(response)
+    }
+
+    /// Tor clients used for each isolation group
+    pub fn clients(&self) -> &IsolatedClients {
+        &self.clients
+    }
+
+    /// Number of idle connections currently held for all hosts
+    pub fn idle_count(&self) -> usize {
+        self.idle.len()
+    }
+
+    /// Wait for a free slot among the concurrent connections to one host
+    async fn host_slot(&self, key: &str) -> Result<HostSlot<'_>> {
+        let semaphore = self
+            .limits
+            .lock()
+            .unwrap()
+            .entry(key.to_string())
+            .or_insert_with(|| Arc::new(Semaphore::new(self.config.max_per_host.max(1))))
+            .clone();
+        let mut slot = HostSlot {
+            limits: &self.limits,
+            key: key.to_string(),
+            semaphore: semaphore.clone(),
+            permit: None,
+        };
+        // If this fails or is cancelled, the dropped slot removes the entry again if unused
+        slot.permit = Some(semaphore.acquire_owned().await?);
+        Ok(slot)
+    }
+}
+
+/// A request's slot among those for its pool key, released when dropped
+struct HostSlot<'a> {
+    limits: &'a Mutex<HashMap<String, Arc<Semaphore>>>,
+    key: String,
+    semaphore: Arc<Semaphore>,
+    permit: Option<OwnedSemaphorePermit>,
+}
+
+impl Drop for HostSlot<'_> {
+    fn drop(&mut self) {
+        self.permit.take();
+        let mut limits = self.limits.lock().unwrap();
+        // New references are only made under the lock, so two (the map's and
+        // ours) means no other request for this key is in flight or waiting
+        if Arc::strong_count(&self.semaphore) == 2 {
+            limits.remove(&self.key);
+        }
+    }
+}
+
+/// Check that an idle connection has neither been closed by the server nor
+/// received unexpected bytes, without blocking
+fn is_alive(conn: &mut Connection) -> bool {
+    if !conn.buffer().is_empty() {
+        return false;
+    }
+    // A pending read means the connection is open and quiet
+    conn.fill_buf().now_or_never().is_none()
+}
+
+/// Pool key identifying the origin of a URL and the isolation group
+fn pool_key(url: &Url, isolation: &Isolation) -> Result<String> {
+    let host = url.host_str().ok_or_else(|| anyhow!("No host in URL"))?;
+    let port = url
+        .port_or_known_default()
+        .ok_or_else(|| anyhow!("No port for URL scheme {}", url.scheme()))?;
+    let origin = format!("{}://{}:{}", url.scheme(), host, port);
+    Ok(match isolation {
+        Isolation::Group(name) => format!("{} [{}]", origin, name),
+        _ => origin,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::{duplex, AsyncReadExt, AsyncWriteExt, DuplexStream};
+
+    /// A connection, and the server's end of it
+    fn connection() -> (Connection, DuplexStream) {
+        let (client, server) = duplex(64);
+        (BufReader::new(Box::new(client)), server)
+    }
+
+    #[tokio::test]
+    async fn reuses_most_recent_connection() {
+        let idle = IdleConnections::new(Duration::from_secs(60), 2);
+        let (first, mut first_server) = connection();
+        let (second, _second_server) = connection();
+        idle.put("a", first);
+        idle.put("a", second);
+        assert_eq!(idle.len(), 2);
+        assert!(idle.take(&"b").is_none());
+
+        // The second connection goes first; then the first one
+        let mut conn = idle.take(&"a").unwrap();
+        conn.get_mut().write_all(b"x").await.unwrap();
+        let mut conn = idle.take(&"a").unwrap();
+        conn.get_mut().write_all(b"1").await.unwrap();
+        let mut byte = [0u8; 1];
+        first_server.read_exact(&mut byte).await.unwrap();
+        assert_eq!(&byte, b"1");
+        assert!(idle.take(&"a").is_none());
+    }
+
+    #[tokio::test]
+    async fn evicts_oldest_over_limit() {
+        let idle = IdleConnections::new(Duration::from_secs(60), 1);
+        let (first, mut first_server) = connection();
+        let (second, _second_server) = connection();
+        idle.put("a", first);
+        idle.put("a", second);
+        assert_eq!(idle.len(), 1);
+        // The evicted connection was closed
+        let mut buffer = Vec::new();
+        assert_eq!(first_server.read_to_end(&mut buffer).await.unwrap(), 0);
+    }
+
+    #[tokio::test]
+    async fn drops_expired_and_stale_connections() {
+        let expiring = IdleConnections::new(Duration::ZERO, 4);
+        let (conn, _server) = connection();
+        expiring.put("a", conn);
+        assert!(expiring.take(&"a").is_none());
+
+        let idle = IdleConnections::new(Duration::from_secs(60), 4);
+        let (closed, closed_server) = connection();
+        let (chatty, mut chatty_server) = connection();
+        drop(closed_server);
+        chatty_server.write_all(b"unexpected").await.unwrap();
+        idle.put("a", closed);
+        idle.put("a", chatty);
+        assert!(idle.take(&"a").is_none());
+        assert_eq!(idle.len(), 0);
+    }
+
+    #[tokio::test]
+    async fn sweeps_expired_connections_of_other_keys() {
+        let idle = IdleConnections::new(Duration::from_millis(20), 4);
+        let (old, mut old_server) = connection();
+        idle.put("a", old);
+        tokio::time::sleep(Duration::from_millis(30)).await;
+
+        let (new, _new_server) = connection();
+        idle.put("b", new);
+        assert_eq!(idle.len(), 1);
+        let mut buffer = Vec::new();
+        assert_eq!(old_server.read_to_end(&mut buffer).await.unwrap(), 0);
+    }
+
+    #[tokio::test]
+    async fn removes_matching_keys() {
+        let idle = IdleConnections::new(Duration::from_secs(60), 4);
+        let (first, _first_server) = connection();
+        let (second, _second_server) = connection();
+        let (third, _third_server) = connection();
+        idle.put(("a", 1), first);
+        idle.put(("b", 1), second);
+        idle.put(("a", 2), third);
+        idle.remove_where(|(_, group)| *group == 1);
+        assert_eq!(idle.len(), 1);
+        assert!(idle.take(&("a", 2)).is_some());
+    }
+
+    #[tokio::test]
+    async fn zero_limit_keeps_nothing() {
+        let idle = IdleConnections::new(Duration::from_secs(60), 0);
+        let (conn, _server) = connection();
+        idle.put("a", conn);
+        assert_eq!(idle.len(), 0);
+    }
+}
@@ -0,0 +1,380 @@
+use anyhow::{anyhow, Result};
+use arti_client::isolation::IsolationHelper;
+use arti_client::{StreamPrefs, TorClient};
+use log::{debug, error, info};
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::{oneshot, OwnedSemaphorePermit, Semaphore};
+use tor_socksproto::{
+    Handshake, NextStep, SocksAddr, SocksAuth, SocksCmd, SocksProxyHandshake, SocksRequest,
+    SocksStatus,
+};
+
+use crate::http_socks_bridge::{relay_data, SocksError};
+
+/// Default port for the embedded SOCKS server (the Tor Browser port, so it
+/// doesn't clash with a system Tor daemon on 9050)
+const DEFAULT_PORT: u16 = 9150;
+/// Default limit of concurrent client connections
+const DEFAULT_MAX_CONNECTIONS: usize = 256;
+/// Default time for a client to complete its SOCKS handshake
+const DEFAULT_HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Configuration for the embedded SOCKS server
+#[derive(Debug, Clone)]
+pub struct SocksServerConfig {
+    /// Local address to bind the SOCKS server
+    pub bind_addr: SocketAddr,
+    /// Stream preferences for every connection. They replace the client's own,
+    /// so pass the ones it was created with (e.g. `TorSettings::stream_prefs()`).
+    pub prefs: StreamPrefs,
+    /// Maximum number of client connections served at once (`None` for no limit).
+    /// While it is reached the server stops accepting, so clients wait in the listen backlog.
+    pub max_connections: Option<usize>,
+    /// Time a client gets to send its SOCKS request before it is disconnected
+    pub handshake_timeout: Option<Duration>,
+}
+
+impl SocksServerConfig {
+    /// Listen on the default port with the given stream preferences
+    pub fn new(prefs: StreamPrefs) -> Self {
+        Self {
+            bind_addr: format!("127.0.0.1:{}", DEFAULT_PORT).parse().unwrap(),
+            prefs,
+            max_connections: Some(DEFAULT_MAX_CONNECTIONS),
+            handshake_timeout: Some(DEFAULT_HANDSHAKE_TIMEOUT),
+        }
+    }
+}
+
+/// Stream isolation by SOCKS authentication, like Tor's `IsolateSOCKSAuth`:
+/// streams with the same username/password (or SOCKS4 user id) may share a circuit
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct AuthIsolation(SocksAuth);
+
+impl IsolationHelper for AuthIsolation {
+    fn compatible_same_type(&self, other: &Self) -> bool {
+        self == other
+    }
+
+    fn join_same_type(&self, other: &Self) -> Option<Self> {
+        (self == other).then(|| self.clone())
+    }
+}
+
+/// Starts a SOCKS5/SOCKS4a server that opens its streams through `tor_client`,
+/// so local tools (bitcoind, Electrum clients, ...) can use the embedded Tor.
+/// Returns the address the server is listening on and a shutdown channel.
+pub async fn start_socks_server(
+    tor_client: TorClient<tor_rtcompat::PreferredRuntime>,
+    config: SocksServerConfig,
+) -> Result<(SocketAddr, oneshot::Sender<()>)> {
+    let listener = TcpListener::bind(config.bind_addr).await?;
+    let local_addr = listener.local_addr()?;
+    info!("SOCKS server listening on {}", local_addr);
+
+    let (shutdown_tx, mut shutdown_rx) = oneshot::channel();
+    let tor_client = Arc::new(tor_client);
+    let prefs = Arc::new(config.prefs);
+    let handshake_timeout = config.handshake_timeout;
+    let connections = config
+        .max_connections
+        .map(|max| Arc::new(Semaphore::new(max)));
+
+    tokio::spawn(async move {
+        loop {
+            tokio::select! {
+                accept_result = accept(&listener, connections.as_ref()) => {
+                    match accept_result {
+                        Ok((stream, addr, permit)) => {
+                            debug!("New SOCKS connection from {}", addr);
+                            let tor_client = tor_client.clone();
+                            let prefs = prefs.clone();
+                            tokio::spawn(async move {
+                                let _permit = permit;
+                                let result =
+                                    handle_socks_connection(stream, &tor_client, &prefs, handshake_timeout)
+                                        .await;
+                                if let Err(e) = result {
+                                    error!("Error handling SOCKS connection from {}: {}", addr, e);
+                                }
+                            });
+                        }
+                        Err(e) => {
+                            error!("Error accepting SOCKS connection: {}", e);
+                        }
+                    }
+                }
+                _ = &mut shutdown_rx => {
+                    info!("Shutdown signal received, stopping SOCKS server");
+                    break;
+                }
+            }
+        }
+    });
+
+    Ok((local_addr, shutdown_tx))
+}
+
+/// Accept the next connection, first waiting for a free slot if connections are limited
+async fn accept(
+    listener: &TcpListener,
+    connections: Option<&Arc<Semaphore>>,
+) -> std::io::Result<(TcpStream, SocketAddr, Option<OwnedSemaphorePermit>)> {
+    let permit = match connections {
+        // The semaphore is never closed
+        Some(connections) => Some(connections.clone().acquire_owned().await.unwrap()),
+        None => None,
+    };
+    let (stream, addr) = listener.accept().await?;
+    Ok((stream, addr, permit))
+}
+
+/// Run the SOCKS handshake for one client and serve its request
+async fn handle_socks_connection(
+    mut client_stream: TcpStream,
+    tor_client: &TorClient<tor_rtcompat::PreferredRuntime>,
+    prefs: &StreamPrefs,
+    handshake_timeout: Option<Duration>,
+) -> Result<()> {
+    let request = read_socks_request(&mut client_stream, handshake_timeout).await?;
+    let target = match request.addr() {
+        SocksAddr::Hostname(host) => host.as_ref().to_string(),
+        SocksAddr::Ip(ip) => ip.to_string(),
+    };
+    let port = request.port();
+
+    let mut prefs = prefs.clone();
+    if *request.auth() != SocksAuth::NoAuth {
+        prefs.set_isolation(AuthIsolation(request.auth().clone()));
+    }
+
+    match request.command() {
+        SocksCmd::CONNECT => {
+            info!("SOCKS CONNECT to {}:{}", target, port);
+            let stream = match tor_client
+                .connect_with_prefs((target.as_str(), port), &prefs)
+                .await
+            {
+                Ok(stream) => stream,
+                Err(e) => {
+                    let status = SocksError::from_arti_error(&e).reply_code().into();
+                    send_reply(&mut client_stream, &request, status, None).await?;
+                    return Err(anyhow!("Connection to {}:{} failed: {}", target, port, e));
+                }
+            };
+            send_reply(&mut client_stream, &request, SocksStatus::SUCCEEDED, None).await?;
+            let stats = relay_data(client_stream, Box::new(stream), None).await?;
+            info!("SOCKS stream to {}:{} closed: {}", target, port, stats);
+            Ok(())
+        }
+        SocksCmd::RESOLVE => {
+            info!("SOCKS RESOLVE for {}", target);
+            match tor_client.resolve_with_prefs(&target, &prefs).await {
+                Ok(addrs) if !addrs.is_empty() => {
+                    let addr = SocksAddr::Ip(addrs[0]);
+                    send_reply(
+                        &mut client_stream,
+                        &request,
+                        SocksStatus::SUCCEEDED,
+                        Some(&addr),
+                    )
+                    .await
+                }
+                Ok(_) => {
+                    send_reply(
+                        &mut client_stream,
+                        &request,
+                        SocksStatus::HOST_UNREACHABLE,
+                        None,
+                    )
+                    .await
+                }
+                Err(e) => {
+                    let status = SocksError::from_arti_error(&e).reply_code().into();
+                    send_reply(&mut client_stream, &request, status, None).await?;
+                    Err(anyhow!("Resolving {} failed: {}", target, e))
+                }
+            }
+        }
+        other => {
+            send_reply(
+                &mut client_stream,
+                &request,
+                SocksStatus::COMMAND_NOT_SUPPORTED,
+                None,
+            )
+            .await?;
+            Err(anyhow!("Unsupported SOCKS command {}", other))
+        }
+    }
+}
+
+/// Read the client's SOCKS4/4a/5 handshake up to its request, giving up after
+/// `timeout`. Reads are precise, so data the client sends after the request
+/// stays in the socket.
+async fn read_socks_request(
+    client_stream: &mut TcpStream,
+    timeout: Option<Duration>,
+) -> Result<SocksRequest> {
+    match timeout {
+        Some(limit) => tokio::time::timeout(limit, socks_handshake(client_stream))
+            .await
+            .map_err(|_| anyhow!("SOCKS handshake timed out after {:?}", limit))?,
+        None => socks_handshake(client_stream).await,
+    }
+}
+
+/// Step the handshake until the client's request is complete
+async fn socks_handshake(client_stream: &mut TcpStream) -> Result<SocksRequest> {
+    let mut handshake = SocksProxyHandshake::new();
+    let mut buffer = tor_socksproto::Buffer::new_precise();
+    loop {
+        match handshake.step(&mut buffer)? {
+            NextStep::Send(data) => client_stream.write_all(&data).await?,
+            NextStep::Recv(mut recv) => {
+                let n = client_stream.read(recv.buf()).await?;
+                recv.note_received(n)?;
+            }
+            NextStep::Finished(finished) => return Ok(finished.into_output()?),
+        }
+    }
+}
+
+/// Send the reply to a SOCKS request
+async fn send_reply(
+    client_stream: &mut TcpStream,
+    request: &SocksRequest,
+    status: SocksStatus,
+    addr: Option<&SocksAddr>,
+) -> Result<()> {
+    let reply = request
+        .reply(status, addr)
+        .map_err(|e| anyhow!("Cannot encode SOCKS reply: {}", e))?;
+    client_stream.write_all(&reply).await?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Connect a client to a loopback listener, returning both ends
+    async fn socket_pair() -> (TcpStream, TcpStream) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let client = TcpStream::connect(listener.local_addr().unwrap())
+            .await
+            .unwrap();
+        let (server, _) = listener.accept().await.unwrap();
+        (client, server)
+    }
+
+    /// Read what is left in the socket after the client closed its side
+    async fn remaining(mut stream: TcpStream) -> Vec<u8> {
+        let mut rest = Vec::new();
+        stream.read_to_end(&mut rest).await.unwrap();
+        rest
+    }
+
+    #[tokio::test]
+    async fn socks5_request_with_password() {
+        let (mut client, mut server) = socket_pair().await;
+        let mut sent = vec![0x05, 0x01, 0x02];
+        sent.extend_from_slice(b"\x01\x08wallet-1\x07isolate");
+        sent.extend_from_slice(&[0x05, 0x01, 0x00, 0x03, 11]);
+        sent.extend_from_slice(b"example.com");
+        sent.extend_from_slice(&443u16.to_be_bytes());
+        sent.extend_from_slice(b"GET / HTTP/1.1\r\n");
+        client.write_all(&sent).await.unwrap();
+        client.shutdown().await.unwrap();
+
+        let request = read_socks_request(&mut server, None).await.unwrap();
+        assert_eq!(request.command(), SocksCmd::CONNECT);
+        assert_eq!(request.addr().to_string(), "example.com");
+        assert_eq!(request.port(), 443);
+        assert_eq!(
+            *request.auth(),
+            SocksAuth::Username(b"wallet-1".to_vec(), b"isolate".to_vec())
+        );
+        assert_eq!(remaining(server).await, b"GET / HTTP/1.1\r\n");
+
+        // Method selection, then the authentication result
+        let mut replies = Vec::new();
+        client.read_to_end(&mut replies).await.unwrap();
+        assert_eq!(replies, [0x05, 0x02, 0x01, 0x00]);
+    }
+
+    #[tokio::test]
+    async fn socks5_request_without_auth() {
+        let (mut client, mut server) = socket_pair().await;
+        let mut sent = vec![0x05, 0x01, 0x00];
+        sent.extend_from_slice(&[0x05, 0xF0, 0x00, 0x01, 10, 0, 0, 1]);
+        sent.extend_from_slice(&53u16.to_be_bytes());
+        sent.extend_from_slice(b"rest");
+        client.write_all(&sent).await.unwrap();
+        client.shutdown().await.unwrap();
+
+        let request = read_socks_request(&mut server, None).await.unwrap();
+        assert_eq!(request.command(), SocksCmd::RESOLVE);
+        assert_eq!(request.addr().to_string(), "10.0.0.1");
+        assert_eq!(request.port(), 53);
+        assert_eq!(*request.auth(), SocksAuth::NoAuth);
+        assert_eq!(remaining(server).await, b"rest");
+    }
+
+    #[tokio::test]
+    async fn socks4a_request() {
+        let (mut client, mut server) = socket_pair().await;
+        let mut sent = vec![0x04, 0x01];
+        sent.extend_from_slice(&8333u16.to_be_bytes());
+        // 0.0.0.x asks the proxy to resolve the hostname that follows the user id
+        sent.extend_from_slice(&[0, 0, 0, 1]);
+        sent.extend_from_slice(b"wallet-1\0");
+        sent.extend_from_slice(b"node.example.onion\0");
+        sent.extend_from_slice(b"version");
+        client.write_all(&sent).await.unwrap();
+        client.shutdown().await.unwrap();
+
+        let request = read_socks_request(&mut server, None).await.unwrap();
+        assert_eq!(request.command(), SocksCmd::CONNECT);
+        assert_eq!(request.addr().to_string(), "node.example.onion");
+        assert_eq!(request.port(), 8333);
+        assert_eq!(*request.auth(), SocksAuth::Socks4(b"wallet-1".to_vec()));
+        assert_eq!(remaining(server).await, b"version");
+    }
+
+    #[tokio::test]
+    async fn stalled_handshake_times_out() {
+        let (mut client, mut server) = socket_pair().await;
+        // Greeting without the request
+        client.write_all(&[0x05, 0x01, 0x00]).await.unwrap();
+
+        let error = read_socks_request(&mut server, Some(Duration::from_millis(50)))
+            .await
+            .unwrap_err();
+        assert!(error.to_string().contains("timed out"), "{}", error);
+    }
+
+    #[tokio::test]
+    async fn accept_waits_for_a_free_slot() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let connections = Arc::new(Semaphore::new(1));
+        let _first_client = TcpStream::connect(addr).await.unwrap();
+        let _second_client = TcpStream::connect(addr).await.unwrap();
+
+        let (_, _, first) = accept(&listener, Some(&connections)).await.unwrap();
+        let waiting = tokio::time::timeout(
+            Duration::from_millis(50),
+            accept(&listener, Some(&connections)),
+        );
+        assert!(waiting.await.is_err());
+
+        drop(first);
+        let (_, _, second) = accept(&listener, Some(&connections)).await.unwrap();
+        assert!(second.is_some());
+    }
+}
@@ -0,0 +1,49 @@
+use anyhow::{anyhow, Result};
+use log::debug;
+use std::sync::{Arc, OnceLock};
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio_rustls::client::TlsStream;
+use tokio_rustls::rustls::{ClientConfig, OwnedTrustAnchor, RootCertStore, ServerName};
+use tokio_rustls::TlsConnector;
+
+/// Shared rustls client configuration trusting the webpki root certificates
+fn client_config() -> Arc<ClientConfig> {
+    static CONFIG: OnceLock<Arc<ClientConfig>> = OnceLock::new();
+    CONFIG
+        .get_or_init(|| {
+            let mut root_store = RootCertStore::empty();
+            root_store.add_trust_anchors(webpki_roots::TLS_SERVER_ROOTS.iter().map(|ta| {
+                OwnedTrustAnchor::from_subject_spki_name_constraints(
+                    ta.subject,
+                    ta.spki,
+                    ta.name_constraints,
+                )
+            }));
+            let config = ClientConfig::builder()
+                .with_safe_defaults()
+                .with_root_certificates(root_store)
+                .with_no_client_auth();
+            Arc::new(config)
+        })
+        .clone()
+}
+
+/// Run a TLS handshake over `stream` (e.g. an Arti `DataStream`), sending
+/// `host` as SNI and verifying the server certificate against it
+pub async fn connect_tls<S>(stream: S, host: &str) -> Result<TlsStream<S>>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let server_name =
+        ServerName::try_from(host).map_err(|_| anyhow!("Invalid TLS server name: {}", host))?;
+    debug!("Starting TLS handshake with {}", host);
+
+    let connector = TlsConnector::from(client_config());
+    let tls_stream = connector
+        .connect(server_name, stream)
+        .await
+        .map_err(|e| anyhow!("TLS handshake with {} failed: {}", host, e))?;
+    debug!("TLS handshake with {} complete", host);
+
+    Ok(tls_stream)
+}
@@ -1,108 +1,403 @@
-use anyhow::{anyhow, Result};
-use arti_client::{config::TorClientConfig, TorClient};
+use anyhow::{anyhow, Context, Result};
+use arti_client::config::pt::TransportConfigBuilder;
+use arti_client::config::{BridgeConfigBuilder, CfgPath, TorClientConfig};
+use arti_client::status::BootstrapStatus;
+use arti_client::{BootstrapBehavior, CountryCode, StreamPrefs, TorClient};
+use futures::{Stream, StreamExt};
 use log::{debug, info};
+use std::collections::HashMap;
+use std::path::PathBuf;
+use std::sync::Mutex;
 use std::time::Duration;
-use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::io::{AsyncRead, AsyncWrite, AsyncWriteExt, BufReader};
+use tokio::sync::oneshot;
+
+use crate::http::{
+    read_response_keep_alive, HeaderPolicy, HttpRequest, HttpResponse, HttpStream, Isolation,
+};
+use crate::tls::connect_tls;
+
+/// A pluggable transport client binary (e.g. obfs4proxy, snowflake-client)
+#[derive(Debug, Clone)]
+pub struct PluggableTransport {
+    /// Transport protocols provided by the binary, e.g. `["obfs4"]`
+    pub protocols: Vec<String>,
+    /// Name or absolute path of the binary
+    pub path: PathBuf,
+    /// Command line arguments passed to the binary
+    pub arguments: Vec<String>,
+    /// Launch the binary at startup instead of on first use
+    pub run_on_startup: bool,
+}
+
+/// Settings for the embedded Tor client. `Default` matches Arti's defaults.
+#[derive(Debug, Clone)]
+pub struct TorSettings {
+    /// Directory for persistent state (guards, etc.), Arti's default if `None`
+    pub state_dir: Option<PathBuf>,
+    /// Directory for the directory cache, Arti's default if `None`
+    pub cache_dir: Option<PathBuf>,
+    /// Bridge lines, e.g. `obfs4 192.0.2.55:38114 <fingerprint> cert=... iat-mode=0`.
+    /// When non-empty, Tor is only reached through these bridges.
+    pub bridges: Vec<String>,
+    /// Pluggable transport binaries needed by the bridges
+    pub pluggable_transports: Vec<PluggableTransport>,
+    /// Two-letter country code exits should be in.
+    /// Arti supports a single exit country per stream.
+    pub exit_country: Option<String>,
+    /// Timeout for opening a stream to the target host
+    pub connect_timeout: Option<Duration>,
+    /// Timeout for DNS resolution through Tor
+    pub resolve_timeout: Option<Duration>,
+    /// Whether `.onion` addresses may be connected to
+    pub allow_onion_services: bool,
+}
+
+impl Default for TorSettings {
+    fn default() -> Self {
+        Self {
+            state_dir: None,
+            cache_dir: None,
+            bridges: Vec::new(),
+            pluggable_transports: Vec::new(),
+            exit_country: None,
+            connect_timeout: None,
+            resolve_timeout: None,
+            allow_onion_services: true,
+        }
+    }
+}
+
+impl TorSettings {
+    /// Build the Arti client configuration for these settings
+    pub fn tor_config(&self) -> Result<TorClientConfig> {
+        let mut builder = TorClientConfig::builder();
+
+        if let Some(state_dir) = &self.state_dir {
+            builder
+                .storage()
+                .state_dir(CfgPath::new_literal(state_dir.clone()));
+        }
+        if let Some(cache_dir) = &self.cache_dir {
+            builder
+                .storage()
+                .cache_dir(CfgPath::new_literal(cache_dir.clone()));
+        }
+
+        for line in &self.bridges {
+            let bridge: BridgeConfigBuilder = line
+                .parse()
+                .with_context(|| format!("Invalid bridge line: {}", line))?;
+            builder.bridges().bridges().push(bridge);
+        }
+        for transport in &self.pluggable_transports {
+            let mut transport_builder = TransportConfigBuilder::default();
+            let protocols = transport
+                .protocols
+                .iter()
+                .map(|p| p.parse())
+                .collect::<Result<Vec<_>, _>>()
+                .map_err(|e| anyhow!("Invalid pluggable transport protocol: {}", e))?;
+            transport_builder
+                .protocols(protocols)
+                .path(CfgPath::new_literal(transport.path.clone()))
+                .arguments(transport.arguments.clone())
+                .run_on_startup(transport.run_on_startup);
+            builder.bridges().transports().push(transport_builder);
+        }
+
+        if let Some(timeout) = self.connect_timeout {
+            builder.stream_timeouts().connect_timeout(timeout);
+        }
+        if let Some(timeout) = self.resolve_timeout {
+            builder.stream_timeouts().resolve_timeout(timeout);
+        }
+        builder
+            .address_filter()
+            .allow_onion_addrs(self.allow_onion_services);
+
+        builder
+            .build()
+            .map_err(|e| anyhow!("Invalid Tor configuration: {}", e))
+    }
+
+    /// Stream preferences applied to every connection made by the client
+    pub fn stream_prefs(&self) -> Result<StreamPrefs> {
+        let mut prefs = StreamPrefs::new();
+        if let Some(country) = &self.exit_country {
+            let country: CountryCode = country
+                .parse()
+                .map_err(|e| anyhow!("Invalid exit country {:?}: {}", country, e))?;
+            prefs.exit_country(country);
+        }
+        Ok(prefs)
+    }
+}
 
 /// Create and bootstrap a Tor client
-pub async fn create_tor_client() -> Result<TorClient<tor_rtcompat::PreferredRuntime>> {
-    let config = TorClientConfig::builder()
-        // I can add any config options here
-        .build()
-        .expect("Failed to build config");
+pub async fn create_tor_client(
+    settings: &TorSettings,
+) -> Result<TorClient<tor_rtcompat::PreferredRuntime>> {
+    let config = settings.tor_config()?;
+    let prefs = settings.stream_prefs()?;
 
     // Create the Tor client with the configuration
     info!("Creating and bootstrapping Tor client...");
-    let tor_client = TorClient::create_bootstrapped(config).await?;
+    let mut tor_client = TorClient::create_bootstrapped(config).await?;
+    tor_client.set_stream_prefs(prefs);
     info!("Tor client successfully bootstrapped!");
 
     Ok(tor_client)
 }
 
+/// Create a Tor client without bootstrapping it. Nothing touches the network
+/// until [`bootstrap_tor_client`] is called; connections fail before that.
+pub fn create_unbootstrapped_tor_client(
+    settings: &TorSettings,
+) -> Result<TorClient<tor_rtcompat::PreferredRuntime>> {
+    let config = settings.tor_config()?;
+    let prefs = settings.stream_prefs()?;
+
+    info!("Creating unbootstrapped Tor client...");
+    let mut tor_client = TorClient::builder()
+        .config(config)
+        .bootstrap_behavior(BootstrapBehavior::Manual)
+        .create_unbootstrapped()?;
+    tor_client.set_stream_prefs(prefs);
+
+    Ok(tor_client)
+}
+
+/// Bootstrap a Tor client, giving up early if `cancel` fires (or its sender is dropped)
+pub async fn bootstrap_tor_client(
+    tor_client: &TorClient<tor_rtcompat::PreferredRuntime>,
+    cancel: oneshot::Receiver<()>,
+) -> Result<()> {
+    info!("Bootstrapping Tor client...");
+    tokio::select! {
+        result = tor_client.bootstrap() => {
+            result?;
+            info!("Tor client successfully bootstrapped!");
+            Ok(())
+        }
+        _ = cancel => {
+            info!("Tor bootstrap cancelled");
+            Err(anyhow!("Tor bootstrap cancelled"))
+        }
+    }
+}
+
+/// Snapshot of bootstrap progress, suitable for a "connecting to Tor" indicator
+#[derive(Debug, Clone, PartialEq)]
+pub struct BootstrapProgress {
+    /// Rough progress from 0 to 100
+    pub percent: u8,
+    /// Whether the client can carry traffic now
+    pub ready: bool,
+    /// Why bootstrapping seems stuck, if it does
+    pub blocked: Option<String>,
+    /// Human readable connection and directory status from Arti
+    pub description: String,
+}
+
+impl From<&BootstrapStatus> for BootstrapProgress {
+    fn from(status: &BootstrapStatus) -> Self {
+        Self {
+            percent: (status.as_frac() * 100.0).round().clamp(0.0, 100.0) as u8,
+            ready: status.ready_for_traffic(),
+            blocked: status.blocked().map(|b| b.to_string()),
+            description: status.to_string(),
+        }
+    }
+}
+
+/// Current bootstrap progress of a Tor client
+pub fn bootstrap_progress(
+    tor_client: &TorClient<tor_rtcompat::PreferredRuntime>,
+) -> BootstrapProgress {
+    BootstrapProgress::from(&tor_client.bootstrap_status())
+}
+
+/// Stream of bootstrap progress updates. Ends when the client is dropped.
+pub fn bootstrap_progress_events(
+    tor_client: &TorClient<tor_rtcompat::PreferredRuntime>,
+) -> impl Stream<Item = BootstrapProgress> + Send + Unpin {
+    tor_client
+        .bootstrap_events()
+        .map(|status| BootstrapProgress::from(&status))
+}
+
+/// Wait until the client is ready for traffic, without driving the bootstrap
+/// itself (e.g. while another task runs [`bootstrap_tor_client`])
+pub async fn wait_until_ready(
+    tor_client: &TorClient<tor_rtcompat::PreferredRuntime>,
+) -> Result<()> {
+    if tor_client.bootstrap_status().ready_for_traffic() {
+        return Ok(());
+    }
+    let mut events = tor_client.bootstrap_events();
+    while let Some(status) = events.next().await {
+        if status.ready_for_traffic() {
+            return Ok(());
+        }
+    }
+    Err(anyhow!("Tor client shut down before becoming ready"))
+}
+
+/// Tor clients whose streams are isolated from each other per named group
+/// (e.g. per wallet, per descriptor), all sharing one bootstrapped client
+pub struct IsolatedClients {
+    base: TorClient<tor_rtcompat::PreferredRuntime>,
+    groups: Mutex<HashMap<String, TorClient<tor_rtcompat::PreferredRuntime>>>,
+}
+
+impl IsolatedClients {
+    pub fn new(base: TorClient<tor_rtcompat::PreferredRuntime>) -> Self {
+        Self {
+            base,
+            groups: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// The shared, non-isolated client
+    pub fn base(&self) -> &TorClient<tor_rtcompat::PreferredRuntime> {
+        &self.base
+    }
+
+    /// Client for a named group; the same name always maps to the same circuits
+    pub fn group(&self, name: &str) -> TorClient<tor_rtcompat::PreferredRuntime> {
+        let mut groups = self.groups.lock().unwrap();
+        groups
+            .entry(name.to_string())
+            .or_insert_with(|| self.base.isolated_client())
+            .clone()
+    }
+
+    /// Client whose streams share no circuit with any other stream
+    pub fn unique(&self) -> TorClient<tor_rtcompat::PreferredRuntime> {
+        self.base.isolated_client()
+    }
+
+    /// Client for the isolation requested by an `HttpRequest`
+    pub fn for_isolation(
+        &self,
+        isolation: &Isolation,
+    ) -> TorClient<tor_rtcompat::PreferredRuntime> {
+        match isolation {
+            Isolation::None => self.base.clone(),
+            Isolation::Group(name) => self.group(name),
+            Isolation::Unique => self.unique(),
+        }
+    }
+
+    /// Forget a group, e.g. when a wallet is closed
+    pub fn remove_group(&self, name: &str) {
+        self.groups.lock().unwrap().remove(name);
+    }
+}
+
 /// Fetch content via Arti Tor client
 pub async fn fetch_via_arti(
     tor_client: &TorClient<tor_rtcompat::PreferredRuntime>,
     url: &str,
-) -> Result<String> {
-    debug!("Fetching URL via Arti: {}", url);
-
-    // Parse the URL
-    let parsed_url = url::Url::parse(url)?;
-    // let host = parsed_url.host_str().unwrap_or("unknown").to_string();
-    let host = parsed_url
-        .host_str()
-        .ok_or_else(|| anyhow!("No host in URL"))?;
-    let port = parsed_url.port().unwrap_or_else(|| {
-        if parsed_url.scheme() == "https" {
-            443
-        } else {
-            80
-        }
-    });
+) -> Result<HttpResponse> {
+    let policy = HeaderPolicy::default();
+    request_via_arti(tor_client, &HttpRequest::get(url), Some(&policy)).await
+}
+
+/// Send an HTTP request via Arti Tor client and parse the response.
+/// Headers are normalized with `header_policy` (`None` sends them as given).
+pub async fn request_via_arti(
+    tor_client: &TorClient<tor_rtcompat::PreferredRuntime>,
+    http_request: &HttpRequest,
+    header_policy: Option<&HeaderPolicy>,
+) -> Result<HttpResponse> {
+    debug!(
+        "Sending {} request via Arti: {}",
+        http_request.method, http_request.url
+    );
+
+    // A one-off request has no group registry, so any isolation request
+    // gets a circuit of its own
+    let isolated;
+    let tor_client = if http_request.isolation == Isolation::None {
+        tor_client
+    } else {
+        isolated = tor_client.isolated_client();
+        &isolated
+    };
+
+    // Parse the URL and open a (TLS) stream to its origin
+    let parsed_url = url::Url::parse(&http_request.url)?;
+    let stream = open_http_stream(tor_client, &parsed_url).await?;
+
+    // Serialize request line, headers and body (with a matching Content-Length)
+    let request = http_request.to_bytes_with_policy(header_policy)?;
+    let is_head = http_request.method.eq_ignore_ascii_case("HEAD");
+    let mut reader = BufReader::new(stream);
+    let (response, _) = exchange(&mut reader, &request, is_head).await?;
+
+    Ok(response)
+}
+
+/// Open a Tor stream to the origin of `url`, wrapped in TLS for `https` URLs
+pub(crate) async fn open_http_stream(
+    tor_client: &TorClient<tor_rtcompat::PreferredRuntime>,
+    url: &url::Url,
+) -> Result<Box<dyn HttpStream>> {
+    let host = url.host_str().ok_or_else(|| anyhow!("No host in URL"))?;
+    let port = url
+        .port()
+        .unwrap_or_else(|| if url.scheme() == "https" { 443 } else { 80 });
     // Format address for Arti in the required format: hostname:port
     let addr = format!("{}:{}", host, port);
     info!("Connecting to Tor address: {}", addr);
 
     // Create a Tor connection to the target
-    let mut stream = tor_client.connect(&addr).await?;
+    let stream = tor_client.connect(&addr).await?;
     debug!("Connection established to target");
 
-    // Format path and query
-    let path = if parsed_url.path().is_empty() {
-        "/"
-    } else {
-        parsed_url.path()
-    };
-    let request_path = if let Some(query) = parsed_url.query() {
-        format!("{}?{}", path, query)
+    // Wrap the stream in TLS for https URLs, verifying the certificate for
+    // the host. IPv6 literals are passed without their brackets.
+    if url.scheme() == "https" {
+        let server_name = match url.host() {
+            Some(url::Host::Ipv6(ip)) => ip.to_string(),
+            _ => host.to_string(),
+        };
+        Ok(Box::new(connect_tls(stream, &server_name).await?))
     } else {
-        path.to_string()
-    };
-
-    // Craft a simple HTTP request
-    // Note: In a real implementation, I would use a proper HTTP client library
-    // This is just for demonstration purposes
-    let request = format!(
-        "GET {} HTTP/1.1\r\n\
-         Host: {}\r\n\
-         User-Agent: minreq-tor-poc/0.1.0\r\n\
-         Accept: */*\r\n\
-         Connection: close\r\n\
-         \r\n",
-        request_path, host
-    );
+        Ok(Box::new(stream))
+    }
+}
 
+/// Write a serialized request to a connection and read back the response.
+/// Also returns whether the connection may be reused afterwards.
+pub(crate) async fn exchange<S>(
+    conn: &mut BufReader<S>,
+    request: &[u8],
+    is_head: bool,
+) -> Result<(HttpResponse, bool)>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
     // Send the request
-    info!("Sending request:\n{}", request);
-    stream.write_all(request.as_bytes()).await?;
+    debug!("Sending request:\n{}", String::from_utf8_lossy(request));
+    conn.get_mut().write_all(request).await?;
+    conn.get_mut().flush().await?;
     info!("Request sent, waiting for response...");
 
     // Read with a much longer timeout
-    let mut response = Vec::new();
-    let mut buffer = vec![0; 4096];
     let timeout = Duration::from_secs(60); // Increased timeout
+    let (response, keep_alive) =
+        match tokio::time::timeout(timeout, read_response_keep_alive(conn, is_head)).await {
+            Ok(result) => result?,
+            Err(_) => return Err(anyhow!("Timeout while reading response")),
+        };
+    info!(
+        "Received response with status {} ({} body bytes)",
+        response.status,
+        response.body.len()
+    );
 
-    let read_future = async {
-        loop {
-            match stream.read(&mut buffer).await {
-                Ok(0) => break, // End of stream
-                Ok(n) => {
-                    response.extend_from_slice(&buffer[..n]);
-                    info!("Read {} bytes from stream", n);
-                }
-                Err(e) => return Err(anyhow!("Error reading from stream: {}", e)),
-            }
-        }
-        Ok(())
-    };
-
-    match tokio::time::timeout(timeout, read_future).await {
-        Ok(result) => result?,
-        Err(_) => return Err(anyhow!("Timeout while reading response")),
-    }
-
-    // Convert the response bytes to a String
-    let response_string = String::from_utf8(response)
-        .map_err(|e| anyhow!("Failed to parse response as UTF-8: {}", e))?;
-    Ok(response_string)
+    Ok((response, keep_alive))
 }
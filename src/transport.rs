@@ -0,0 +1,257 @@
+use anyhow::{anyhow, Result};
+use arti_client::TorClient;
+use async_trait::async_trait;
+use log::debug;
+use std::collections::hash_map::RandomState;
+use std::hash::{BuildHasher, Hasher};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, OnceLock};
+use std::time::Duration;
+use tokio::task;
+use url::Url;
+
+use crate::http::{HeaderPolicy, HttpRequest, HttpResponse, Isolation};
+use crate::http_socks_bridge::{start_http_socks_bridge, BridgeConfig, BridgeHandle};
+use crate::pool::{ArtiConnectionPool, PoolConfig};
+
+/// Default timeout in seconds for minreq based transports
+const DEFAULT_TIMEOUT_SECS: u64 = 30;
+
+/// A way of sending HTTP requests, so callers can switch between
+/// clearnet, the HTTP-SOCKS bridge and Arti without changing code
+#[async_trait]
+pub trait HttpTransport: Send + Sync {
+    /// Send a request and wait for the complete response
+    async fn send(&self, request: HttpRequest) -> Result<HttpResponse>;
+}
+
+/// Plain minreq without any proxy (no Tor)
+pub struct DirectTransport {
+    timeout: u64,
+    header_policy: Option<HeaderPolicy>,
+}
+
+impl DirectTransport {
+    pub fn new() -> Self {
+        Self {
+            timeout: DEFAULT_TIMEOUT_SECS,
+            header_policy: Some(HeaderPolicy::default()),
+        }
+    }
+
+    /// Normalize request headers with `policy` (`None` sends them as given)
+    pub fn with_header_policy(mut self, policy: Option<HeaderPolicy>) -> Self {
+        self.header_policy = policy;
+        self
+    }
+}
+
+impl Default for DirectTransport {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[async_trait]
+impl HttpTransport for DirectTransport {
+    async fn send(&self, request: HttpRequest) -> Result<HttpResponse> {
+        let policy = self.header_policy.as_ref();
+        send_via_minreq(request, None, self.timeout, policy).await
+    }
+}
+
+/// minreq through the HTTP-SOCKS bridge, which forwards to Tor's SOCKS port
+pub struct BridgeTransport {
+    proxy_url: String,
+    timeout: u64,
+    header_policy: Option<HeaderPolicy>,
+    // Dropping the handle stops a bridge that was started by this transport
+    bridge: Option<BridgeHandle>,
+}
+
+impl BridgeTransport {
+    /// Use an already running bridge at `proxy_url` (e.g. `http://127.0.0.1:8118`)
+    pub fn new(proxy_url: &str) -> Self {
+        Self {
+            proxy_url: proxy_url.to_string(),
+            timeout: DEFAULT_TIMEOUT_SECS,
+            header_policy: Some(HeaderPolicy::default()),
+            bridge: None,
+        }
+    }
+
+    /// Start a bridge with the given configuration and send requests through it.
+    /// The bridge stops when the transport is dropped.
+    pub async fn start(config: BridgeConfig) -> Result<Self> {
+        let bridge = start_http_socks_bridge(config).await?;
+        Ok(Self {
+            proxy_url: format!("http://{}", bridge.local_addr()),
+            timeout: DEFAULT_TIMEOUT_SECS,
+            header_policy: Some(HeaderPolicy::default()),
+            bridge: Some(bridge),
+        })
+    }
+
+    /// Normalize request headers with `policy` (`None` sends them as given)
+    pub fn with_header_policy(mut self, policy: Option<HeaderPolicy>) -> Self {
+        self.header_policy = policy;
+        self
+    }
+
+    /// URL of the bridge's HTTP proxy
+    pub fn proxy_url(&self) -> &str {
+        &self.proxy_url
+    }
+
+    /// Shut down the bridge started by [`BridgeTransport::start`], giving open
+    /// connections up to `grace` to finish. Returns how many were cut off.
+    pub async fn shutdown(self, grace: Duration) -> Result<usize> {
+        match self.bridge {
+            Some(bridge) => bridge.shutdown(grace).await,
+            None => Ok(0),
+        }
+    }
+
+    /// Proxy URL carrying the request's isolation as proxy credentials.
+    /// The bridge passes them on to Tor when it runs with
+    /// [`SocksAuth::FromProxyAuthorization`](crate::http_socks_bridge::SocksAuth::FromProxyAuthorization).
+    fn proxy_url_for(&self, isolation: &Isolation) -> Result<String> {
+        let username = match isolation {
+            Isolation::None => return Ok(self.proxy_url.clone()),
+            Isolation::Group(name) => format!("group-{}", name),
+            Isolation::Unique => {
+                static NEXT_UNIQUE: AtomicU64 = AtomicU64::new(0);
+                static SESSION: OnceLock<u64> = OnceLock::new();
+                let session = SESSION.get_or_init(|| RandomState::new().build_hasher().finish());
+                let id = NEXT_UNIQUE.fetch_add(1, Ordering::Relaxed);
+                format!("unique-{:016x}-{}", session, id)
+            }
+        };
+        // Let `Url` percent-encode the username, but format the result by hand:
+        // minreq rejects a trailing `/` after the port
+        let mut url = Url::parse(&self.proxy_url)?;
+        url.set_username(&username)
+            .map_err(|_| anyhow!("Cannot set credentials on proxy URL {}", self.proxy_url))?;
+        let host = url
+            .host_str()
+            .ok_or_else(|| anyhow!("No host in proxy URL {}", self.proxy_url))?;
+        let port = url.port_or_known_default().unwrap_or(80);
+        Ok(format!(
+            "http://{}:isolate@{}:{}",
+            url.username(),
+            host,
+            port
+        ))
+    }
+}
+
+#[async_trait]
+impl HttpTransport for BridgeTransport {
+    async fn send(&self, request: HttpRequest) -> Result<HttpResponse> {
+        let proxy_url = self.proxy_url_for(&request.isolation)?;
+        let policy = self.header_policy.as_ref();
+        send_via_minreq(request, Some(proxy_url), self.timeout, policy).await
+    }
+}
+
+/// Arti `DataStream`s opened by the embedded Tor client, kept alive in a
+/// per-host connection pool
+pub struct ArtiTransport {
+    pool: ArtiConnectionPool,
+}
+
+impl ArtiTransport {
+    pub fn new(tor_client: TorClient<tor_rtcompat::PreferredRuntime>) -> Self {
+        Self::with_pool_config(tor_client, PoolConfig::default())
+    }
+
+    pub fn with_pool_config(
+        tor_client: TorClient<tor_rtcompat::PreferredRuntime>,
+        config: PoolConfig,
+    ) -> Self {
+        Self {
+            pool: ArtiConnectionPool::new(tor_client, config),
+        }
+    }
+}
+
+#[async_trait]
+impl HttpTransport for ArtiTransport {
+    async fn send(&self, request: HttpRequest) -> Result<HttpResponse> {
+        self.pool.send(&request).await
+    }
+}
+
+/// Selects which transport to build
+pub enum TransportConfig {
+    /// Clearnet minreq, see [`DirectTransport`]
+    Direct,
+    /// Start an HTTP-SOCKS bridge, see [`BridgeTransport`]
+    Bridge(Box<BridgeConfig>),
+    /// Use an existing HTTP-SOCKS bridge at this proxy URL
+    ExistingBridge(String),
+    /// Embedded Arti client, see [`ArtiTransport`]
+    Arti(Box<TorClient<tor_rtcompat::PreferredRuntime>>),
+}
+
+impl TransportConfig {
+    /// Build the configured transport
+    pub async fn build(self) -> Result<Arc<dyn HttpTransport>> {
+        Ok(match self {
+            TransportConfig::Direct => Arc::new(DirectTransport::new()),
+            TransportConfig::Bridge(config) => Arc::new(BridgeTransport::start(*config).await?),
+            TransportConfig::ExistingBridge(proxy_url) => {
+                Arc::new(BridgeTransport::new(&proxy_url))
+            }
+            TransportConfig::Arti(tor_client) => Arc::new(ArtiTransport::new(*tor_client)),
+        })
+    }
+}
+
+/// Send a request with minreq on the blocking pool, optionally through an HTTP proxy.
+/// minreq adds `Host` and `Content-Length` itself.
+async fn send_via_minreq(
+    mut request: HttpRequest,
+    proxy_url: Option<String>,
+    timeout: u64,
+    header_policy: Option<&HeaderPolicy>,
+) -> Result<HttpResponse> {
+    debug!(
+        "Sending {} request via minreq: {}",
+        request.method, request.url
+    );
+
+    let method = match request.method.as_str() {
+        "GET" => minreq::Method::Get,
+        "HEAD" => minreq::Method::Head,
+        "POST" => minreq::Method::Post,
+        "PUT" => minreq::Method::Put,
+        "DELETE" => minreq::Method::Delete,
+        other => minreq::Method::Custom(other.to_string()),
+    };
+
+    if let Some(policy) = header_policy {
+        request.headers = policy.apply(&request.headers);
+    }
+
+    // minreq is synchronous, so run it on the blocking pool
+    let response = task::spawn_blocking(move || {
+        let mut minreq_request = minreq::Request::new(method, request.url)
+            .with_timeout(timeout)
+            .with_headers(request.headers.iter());
+        if let Some(proxy_url) = proxy_url {
+            minreq_request = minreq_request.with_proxy(minreq::Proxy::new(proxy_url.as_str())?);
+        }
+        if let Some(body) = request.body {
+            minreq_request = minreq_request.with_body(body);
+        }
+        minreq_request.send()
+    })
+    .await??;
+
+    Ok(HttpResponse {
+        status: response.status_code as u16,
+        headers: response.headers.clone().into_iter().collect(),
+        body: response.into_bytes(),
+    })
+}